@@ -1,132 +1,396 @@
-use micro_uecc_sys;
+use std::fmt;
 
 use anyhow::anyhow;
 
-pub struct UEcckeyPair {
-    pub private_key: String,
-    pub public_key: String,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Curve {
+    Secp160r1,
+    Secp192r1,
+    Secp224r1,
+    Secp256r1,
+    Secp256k1,
 }
 
-pub fn uecc_mkae_key_with_secp256k1() -> anyhow::Result<UEcckeyPair> {
-    if micro_uecc_sys::uECC_SUPPORTS_secp256k1 != 1 {
-        return Err(anyhow::anyhow!("secp256k1 is not supported"));
-    }
-    unsafe {
-        let curve = micro_uecc_sys::uECC_secp256k1();
-        let prlen = micro_uecc_sys::uECC_curve_private_key_size(curve) as usize;
-        let plen = micro_uecc_sys::uECC_curve_public_key_size(curve) as usize;
-        // println!("prlen: {} plen: {}", prlen, plen);
-        let mut private_key_buf = vec![0; prlen];
-        let mut public_key_buf = vec![0; plen];
-        let ret =
-            micro_uecc_sys::uECC_make_key(&mut public_key_buf[0], &mut private_key_buf[0], curve);
-        if ret != 1 {
-            return anyhow::Result::Err(anyhow::anyhow!(
-                "Failed to generate a key pair. Procedure"
-            ));
+impl Curve {
+    pub const ALL: [Curve; 5] = [
+        Curve::Secp160r1,
+        Curve::Secp192r1,
+        Curve::Secp224r1,
+        Curve::Secp256r1,
+        Curve::Secp256k1,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Curve::Secp160r1 => "secp160r1",
+            Curve::Secp192r1 => "secp192r1",
+            Curve::Secp224r1 => "secp224r1",
+            Curve::Secp256r1 => "secp256r1",
+            Curve::Secp256k1 => "secp256k1",
         }
-        let private_key = private_key_buf
-            .iter()
-            .map(|v| format!("{:02x}", *v))
-            .reduce(|cur, next| cur + &next)
-            .ok_or_else(|| anyhow!("Failed to generate a key pair. Procedure"))?;
+    }
+
+    // whether micro-ecc was compiled with this curve
+    pub fn is_supported(&self) -> bool {
+        let supported = match self {
+            Curve::Secp160r1 => micro_uecc_sys::uECC_SUPPORTS_secp160r1,
+            Curve::Secp192r1 => micro_uecc_sys::uECC_SUPPORTS_secp192r1,
+            Curve::Secp224r1 => micro_uecc_sys::uECC_SUPPORTS_secp224r1,
+            Curve::Secp256r1 => micro_uecc_sys::uECC_SUPPORTS_secp256r1,
+            Curve::Secp256k1 => micro_uecc_sys::uECC_SUPPORTS_secp256k1,
+        };
+        supported == 1
+    }
 
-        let public_key = public_key_buf
+    pub fn supported() -> Vec<Curve> {
+        Curve::ALL
             .iter()
-            .map(|v| format!("{:02x}", *v))
-            .reduce(|cur, next| cur + &next)
-            .ok_or_else(|| anyhow!("Failed to generate a key pair. Procedure"))?;
+            .copied()
+            .filter(|c| c.is_supported())
+            .collect()
+    }
+
+    fn raw(&self) -> anyhow::Result<micro_uecc_sys::uECC_Curve> {
+        if !self.is_supported() {
+            return Err(anyhow!("{} is not supported", self.name()));
+        }
+        unsafe {
+            Ok(match self {
+                Curve::Secp160r1 => micro_uecc_sys::uECC_secp160r1(),
+                Curve::Secp192r1 => micro_uecc_sys::uECC_secp192r1(),
+                Curve::Secp224r1 => micro_uecc_sys::uECC_secp224r1(),
+                Curve::Secp256r1 => micro_uecc_sys::uECC_secp256r1(),
+                Curve::Secp256k1 => micro_uecc_sys::uECC_secp256k1(),
+            })
+        }
+    }
+
+    pub fn private_key_size(&self) -> anyhow::Result<usize> {
+        let curve = self.raw()?;
+        unsafe { Ok(micro_uecc_sys::uECC_curve_private_key_size(curve) as usize) }
+    }
+
+    pub fn public_key_size(&self) -> anyhow::Result<usize> {
+        let curve = self.raw()?;
+        unsafe { Ok(micro_uecc_sys::uECC_curve_public_key_size(curve) as usize) }
+    }
+
+    // the shared secret is one coordinate, half of the public key
+    pub fn shared_secret_size(&self) -> anyhow::Result<usize> {
+        Ok(self.public_key_size()? / 2)
+    }
+
+    // r || s, each the size of one coordinate
+    pub fn signature_size(&self) -> anyhow::Result<usize> {
+        self.public_key_size()
+    }
+}
+
+impl fmt::Display for Curve {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|v| format!("{:02x}", v)).collect()
+}
+
+fn decode_hex(s: &str) -> anyhow::Result<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return Err(anyhow!("invalid hex string"));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|err| anyhow!(err)))
+        .collect()
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct PublicKey {
+    curve: Curve,
+    bytes: Vec<u8>,
+}
 
-        Ok(UEcckeyPair {
-            private_key,
-            public_key,
+impl PublicKey {
+    // the uncompressed point (x || y) as produced by micro-ecc, it must lie on the curve
+    pub fn from_bytes(curve: Curve, bytes: &[u8]) -> anyhow::Result<PublicKey> {
+        let raw = curve.raw()?;
+        let size = curve.public_key_size()?;
+        if bytes.len() != size {
+            return Err(anyhow!(
+                "{} public key must be {} bytes, got {}",
+                curve,
+                size,
+                bytes.len()
+            ));
+        }
+        let valid = unsafe { micro_uecc_sys::uECC_valid_public_key(bytes.as_ptr(), raw) };
+        if valid != 1 {
+            return Err(anyhow!("not a valid {} public key", curve));
+        }
+        Ok(PublicKey {
+            curve,
+            bytes: bytes.to_vec(),
         })
     }
+
+    pub fn from_hex(curve: Curve, hex: &str) -> anyhow::Result<PublicKey> {
+        PublicKey::from_bytes(curve, &decode_hex(hex)?)
+    }
+
+    pub fn curve(&self) -> Curve {
+        self.curve
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn to_hex(&self) -> String {
+        encode_hex(&self.bytes)
+    }
+
+    // ECDSA verification of a message hash, a malformed signature is simply invalid
+    pub fn verify(&self, message_hash: &[u8], signature: &[u8]) -> anyhow::Result<bool> {
+        let raw = self.curve.raw()?;
+        if message_hash.is_empty() || signature.len() != self.curve.signature_size()? {
+            return Ok(false);
+        }
+        let ret = unsafe {
+            micro_uecc_sys::uECC_verify(
+                self.bytes.as_ptr(),
+                message_hash.as_ptr(),
+                message_hash.len() as std::os::raw::c_uint,
+                signature.as_ptr(),
+                raw,
+            )
+        };
+        Ok(ret == 1)
+    }
 }
 
-pub fn ucc_shared_secret_whith_secp256k1(
-    pub_key_buf: &mut [u8],
-    priv_key_buf: &mut [u8],
-    secret_buf: &mut [u8],
-) -> anyhow::Result<()> {
-    if micro_uecc_sys::uECC_SUPPORTS_secp256k1 != 1 {
-        return Err(anyhow::anyhow!("secp256k1 is not supported"));
-    }
-    unsafe {
-        let curve = micro_uecc_sys::uECC_secp256k1();
-        let ret = micro_uecc_sys::uECC_shared_secret(
-            &mut pub_key_buf[0],
-            &mut priv_key_buf[0],
-            &mut secret_buf[0],
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PublicKey({}, {})", self.curve, self.to_hex())
+    }
+}
+
+pub struct PrivateKey {
+    curve: Curve,
+    bytes: Vec<u8>,
+}
+
+impl PrivateKey {
+    pub fn from_bytes(curve: Curve, bytes: &[u8]) -> anyhow::Result<PrivateKey> {
+        let size = curve.private_key_size()?;
+        if bytes.len() != size {
+            return Err(anyhow!(
+                "{} private key must be {} bytes, got {}",
+                curve,
+                size,
+                bytes.len()
+            ));
+        }
+        Ok(PrivateKey {
             curve,
-        );
+            bytes: bytes.to_vec(),
+        })
+    }
+
+    pub fn from_hex(curve: Curve, hex: &str) -> anyhow::Result<PrivateKey> {
+        PrivateKey::from_bytes(curve, &decode_hex(hex)?)
+    }
+
+    pub fn curve(&self) -> Curve {
+        self.curve
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn to_hex(&self) -> String {
+        encode_hex(&self.bytes)
+    }
+
+    pub fn public_key(&self) -> anyhow::Result<PublicKey> {
+        let raw = self.curve.raw()?;
+        let mut public_key = vec![0u8; self.curve.public_key_size()?];
+        let ret = unsafe {
+            micro_uecc_sys::uECC_compute_public_key(
+                self.bytes.as_ptr(),
+                public_key.as_mut_ptr(),
+                raw,
+            )
+        };
+        if ret != 1 {
+            return Err(anyhow!("uECC_compute_public_key fail"));
+        }
+        Ok(PublicKey {
+            curve: self.curve,
+            bytes: public_key,
+        })
+    }
+
+    // ECDSA signature (r || s) of a message hash, the caller hashes the message
+    pub fn sign(&self, message_hash: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let raw = self.curve.raw()?;
+        if message_hash.is_empty() {
+            return Err(anyhow!("message hash must not be empty"));
+        }
+        let mut signature = vec![0u8; self.curve.signature_size()?];
+        let ret = unsafe {
+            micro_uecc_sys::uECC_sign(
+                self.bytes.as_ptr(),
+                message_hash.as_ptr(),
+                message_hash.len() as std::os::raw::c_uint,
+                signature.as_mut_ptr(),
+                raw,
+            )
+        };
+        if ret == 1 {
+            Ok(signature)
+        } else {
+            Err(anyhow!("uECC_sign fail"))
+        }
+    }
+
+    pub fn shared_secret(&self, public_key: &PublicKey) -> anyhow::Result<Vec<u8>> {
+        if public_key.curve != self.curve {
+            return Err(anyhow!(
+                "cannot combine a {} private key with a {} public key",
+                self.curve,
+                public_key.curve
+            ));
+        }
+        let raw = self.curve.raw()?;
+        let mut secret = vec![0u8; self.curve.shared_secret_size()?];
+        let ret = unsafe {
+            micro_uecc_sys::uECC_shared_secret(
+                public_key.bytes.as_ptr(),
+                self.bytes.as_ptr(),
+                secret.as_mut_ptr(),
+                raw,
+            )
+        };
         if ret == 1 {
-            Ok(())
+            Ok(secret)
         } else {
             Err(anyhow!("uECC_shared_secret fail"))
         }
     }
 }
 
-pub fn uecc_mkae_key_with_secp256r1() -> anyhow::Result<UEcckeyPair> {
-    if micro_uecc_sys::uECC_SUPPORTS_secp256r1 != 1 {
-        return Err(anyhow::anyhow!("secp256r1 is not supported"));
-    }
-    unsafe {
-        let curve = micro_uecc_sys::uECC_secp256r1();
-        let prlen = micro_uecc_sys::uECC_curve_private_key_size(curve) as usize;
-        let plen = micro_uecc_sys::uECC_curve_public_key_size(curve) as usize;
-        // println!("prlen: {} plen: {}", prlen, plen);
-        let mut private_key_buf = vec![0; prlen];
-        let mut public_key_buf = vec![0; plen];
-        let ret =
-            micro_uecc_sys::uECC_make_key(&mut public_key_buf[0], &mut private_key_buf[0], curve);
-        if ret != 1 {
-            return anyhow::Result::Err(anyhow::anyhow!(
-                "Failed to generate a key pair. Procedure"
-            ));
+impl Drop for PrivateKey {
+    fn drop(&mut self) {
+        for b in self.bytes.iter_mut() {
+            unsafe { std::ptr::write_volatile(b, 0) };
         }
-        let private_key = private_key_buf
-            .iter()
-            .map(|v| format!("{:02x}", *v))
-            .reduce(|cur, next| cur + &next)
-            .ok_or_else(|| anyhow!("Failed to generate a key pair. Procedure"))?;
+    }
+}
 
-        let public_key = public_key_buf
-            .iter()
-            .map(|v| format!("{:02x}", *v))
-            .reduce(|cur, next| cur + &next)
-            .ok_or_else(|| anyhow!("Failed to generate a key pair. Procedure"))?;
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PrivateKey({}, ..)", self.curve)
+    }
+}
+
+#[derive(Debug)]
+pub struct KeyPair {
+    pub private_key: PrivateKey,
+    pub public_key: PublicKey,
+}
 
-        Ok(UEcckeyPair {
-            private_key,
-            public_key,
+impl KeyPair {
+    pub fn generate(curve: Curve) -> anyhow::Result<KeyPair> {
+        let raw = curve.raw()?;
+        let mut private_key = vec![0u8; curve.private_key_size()?];
+        let mut public_key = vec![0u8; curve.public_key_size()?];
+        let ret = unsafe {
+            micro_uecc_sys::uECC_make_key(public_key.as_mut_ptr(), private_key.as_mut_ptr(), raw)
+        };
+        if ret != 1 {
+            return Err(anyhow!("Failed to generate a key pair. Procedure"));
+        }
+        Ok(KeyPair {
+            private_key: PrivateKey {
+                curve,
+                bytes: private_key,
+            },
+            public_key: PublicKey {
+                curve,
+                bytes: public_key,
+            },
         })
     }
 }
 
+pub struct UEcckeyPair {
+    pub private_key: String,
+    pub public_key: String,
+}
+
+impl From<KeyPair> for UEcckeyPair {
+    fn from(pair: KeyPair) -> Self {
+        UEcckeyPair {
+            private_key: pair.private_key.to_hex(),
+            public_key: pair.public_key.to_hex(),
+        }
+    }
+}
+
+fn shared_secret_into(
+    curve: Curve,
+    pub_key_buf: &[u8],
+    priv_key_buf: &[u8],
+    secret_buf: &mut [u8],
+) -> anyhow::Result<()> {
+    let public_key = PublicKey::from_bytes(curve, pub_key_buf)?;
+    let private_key = PrivateKey::from_bytes(curve, priv_key_buf)?;
+    let secret = private_key.shared_secret(&public_key)?;
+    if secret_buf.len() < secret.len() {
+        return Err(anyhow!(
+            "secret buffer must be at least {} bytes",
+            secret.len()
+        ));
+    }
+    secret_buf[..secret.len()].copy_from_slice(&secret);
+    Ok(())
+}
+
+pub fn uecc_mkae_key_with_secp256k1() -> anyhow::Result<UEcckeyPair> {
+    KeyPair::generate(Curve::Secp256k1).map(UEcckeyPair::from)
+}
+
+pub fn ucc_shared_secret_whith_secp256k1(
+    pub_key_buf: &mut [u8],
+    priv_key_buf: &mut [u8],
+    secret_buf: &mut [u8],
+) -> anyhow::Result<()> {
+    shared_secret_into(Curve::Secp256k1, pub_key_buf, priv_key_buf, secret_buf)
+}
+
+pub fn uecc_valid_public_key_with_secp256k1(pub_key_buf: &[u8]) -> anyhow::Result<bool> {
+    Curve::Secp256k1.raw()?;
+    Ok(PublicKey::from_bytes(Curve::Secp256k1, pub_key_buf).is_ok())
+}
+
+pub fn uecc_mkae_key_with_secp256r1() -> anyhow::Result<UEcckeyPair> {
+    KeyPair::generate(Curve::Secp256r1).map(UEcckeyPair::from)
+}
+
 pub fn ucc_shared_secret_whith_secp256r1(
     pub_key_buf: &mut [u8],
     priv_key_buf: &mut [u8],
     secret_buf: &mut [u8],
 ) -> anyhow::Result<()> {
-    if micro_uecc_sys::uECC_SUPPORTS_secp256r1 != 1 {
-        return Err(anyhow::anyhow!("secp256r1 is not supported"));
-    }
-    unsafe {
-        let curve = micro_uecc_sys::uECC_secp256r1();
-        let ret = micro_uecc_sys::uECC_shared_secret(
-            &mut pub_key_buf[0],
-            &mut priv_key_buf[0],
-            &mut secret_buf[0],
-            curve,
-        );
-        if ret == 1 {
-            Ok(())
-        } else {
-            Err(anyhow!("uECC_shared_secret fail"))
-        }
-    }
+    shared_secret_into(Curve::Secp256r1, pub_key_buf, priv_key_buf, secret_buf)
+}
+
+pub fn uecc_valid_public_key_with_secp256r1(pub_key_buf: &[u8]) -> anyhow::Result<bool> {
+    Curve::Secp256r1.raw()?;
+    Ok(PublicKey::from_bytes(Curve::Secp256r1, pub_key_buf).is_ok())
 }
 
 #[cfg(test)]
@@ -153,4 +417,58 @@ mod tests {
             }
         };
     }
+
+    #[test]
+    fn shared_secret_all_curves() {
+        for curve in Curve::supported() {
+            let alice = KeyPair::generate(curve).unwrap();
+            let bob = KeyPair::generate(curve).unwrap();
+            let a = alice.private_key.shared_secret(&bob.public_key).unwrap();
+            let b = bob.private_key.shared_secret(&alice.public_key).unwrap();
+            assert_eq!(a, b);
+            assert_eq!(a.len(), curve.shared_secret_size().unwrap());
+            assert_eq!(alice.private_key.public_key().unwrap(), alice.public_key);
+        }
+    }
+
+    #[test]
+    fn rejects_short_buffers() {
+        let pair = KeyPair::generate(Curve::Secp256k1).unwrap();
+        let short = &pair.public_key.as_bytes()[..10];
+        assert!(PublicKey::from_bytes(Curve::Secp256k1, short).is_err());
+        assert!(PrivateKey::from_bytes(Curve::Secp256k1, &[]).is_err());
+        assert!(
+            ucc_shared_secret_whith_secp256k1(&mut [0u8; 1], &mut [0u8; 1], &mut [0u8; 1]).is_err()
+        );
+        let mut secret = [0u8; 8];
+        assert!(ucc_shared_secret_whith_secp256k1(
+            &mut pair.public_key.as_bytes().to_vec(),
+            &mut pair.private_key.as_bytes().to_vec(),
+            &mut secret
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn sign_verify_all_curves() {
+        let hash = [0x5au8; 32];
+        for curve in Curve::supported() {
+            let pair = KeyPair::generate(curve).unwrap();
+            let signature = pair.private_key.sign(&hash).unwrap();
+            assert_eq!(signature.len(), curve.signature_size().unwrap());
+            assert!(pair.public_key.verify(&hash, &signature).unwrap());
+
+            let mut other_hash = hash;
+            other_hash[0] ^= 1;
+            assert!(!pair.public_key.verify(&other_hash, &signature).unwrap());
+            assert!(!pair.public_key.verify(&hash, &signature[1..]).unwrap());
+        }
+    }
+
+    #[test]
+    fn rejects_key_from_other_curve() {
+        let pair = KeyPair::generate(Curve::Secp256k1).unwrap();
+        let other = KeyPair::generate(Curve::Secp256r1).unwrap();
+        assert!(pair.private_key.shared_secret(&other.public_key).is_err());
+    }
 }
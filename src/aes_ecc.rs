@@ -1,132 +1,268 @@
 use byte_struct::*;
 
-use openssl::symm::{Cipher as AesCipher, Crypter, Mode};
+use openssl::sha::Sha256;
+use openssl::symm::{self, Cipher as AesCipher, Crypter, Mode};
 
-use std::io::{Read, Write};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
 
 use crate::cipher::Cipher;
+use crate::curve::Curve;
+use crate::format::{self, Algorithm, ExtensibleHeader, Extension, Recipient};
 use crate::header;
+use crate::kdf;
+use crate::metadata::{Metadata, MetadataSplitter};
+use crate::stream::{self, StreamDecryptor, StreamEncryptor, Transform, BUFFER_SIZE};
 use crate::utils;
-use crate::version;
+use crate::version::Version;
 
-use micro_uecc_safe;
+use micro_uecc_safe::{PrivateKey, PublicKey};
 
-const BUFFER_SIZE: usize = 4096;
+const V3_KEY_INFO: &[u8] = b"file_cipher v3 aes-256-gcm key";
+const V3_NONCE_INFO: &[u8] = b"file_cipher v3 aes-256-gcm nonce";
+const V3_WRAP_INFO: &[u8] = b"file_cipher v3 key wrap";
+const V3_SIGNATURE_CONTEXT: &[u8] = b"file_cipher v3 signature";
+const V5_WRAP_INFO: &[u8] = b"file_cipher v5 key wrap";
+const V5_SIGNATURE_CONTEXT: &[u8] = b"file_cipher v5 signature";
 
+// The payload key and nonce are derived from the random file key, bound to
+// the whole header including the recipient table.
+fn derive_v3_keys(
+    file_key: &[u8],
+    header: &header::AesECCGcmHeader,
+    recipients: &[u8],
+) -> anyhow::Result<([u8; 32], [u8; header::AES_GCM_NONCE_LEN])> {
+    let info = header.kdf_info();
+    let mut key = [0u8; 32];
+    let mut nonce = [0u8; header::AES_GCM_NONCE_LEN];
+    kdf::hkdf_sha256(
+        file_key,
+        header.salt_bytes(),
+        &[&info, recipients, V3_KEY_INFO],
+        &mut key,
+    )?;
+    kdf::hkdf_sha256(
+        file_key,
+        header.salt_bytes(),
+        &[&info, recipients, V3_NONCE_INFO],
+        &mut nonce,
+    )?;
+    Ok((key, nonce))
+}
+
+// ECIES key schedule: the raw ECDH secret is never used as a key directly.
+// Every wrap key comes from a fresh ephemeral key pair, so the all zero nonce
+// is never reused under the same key.
+fn derive_wrap_key(
+    shared_secret: &[u8],
+    header: &header::AesECCGcmHeader,
+    ephemeral_key: &[u8],
+) -> anyhow::Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    kdf::hkdf_sha256(
+        shared_secret,
+        header.salt_bytes(),
+        &[&header.kdf_info(), ephemeral_key, V3_WRAP_INFO],
+        &mut key,
+    )?;
+    Ok(key)
+}
+// Format 5 leaves the header out of the wrap key, the payload authenticates
+// the whole header as associated data instead.
+fn derive_v5_wrap_key(
+    shared_secret: &[u8],
+    salt: &[u8],
+    ephemeral_key: &[u8],
+) -> anyhow::Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    kdf::hkdf_sha256(
+        shared_secret,
+        salt,
+        &[ephemeral_key, V5_WRAP_INFO],
+        &mut key,
+    )?;
+    Ok(key)
+}
+
+fn wrap_file_key(
+    wrap_key: &[u8; 32],
+    ephemeral_key: &[u8],
+    file_key: &[u8],
+) -> anyhow::Result<[u8; header::WRAPPED_KEY_LEN]> {
+    let mut tag = [0u8; header::AES_GCM_TAG_LEN];
+    let ciphertext = symm::encrypt_aead(
+        AesCipher::aes_256_gcm(),
+        wrap_key,
+        Some(&[0u8; header::AES_GCM_NONCE_LEN]),
+        ephemeral_key,
+        file_key,
+        &mut tag,
+    )?;
+    let mut wrapped = [0u8; header::WRAPPED_KEY_LEN];
+    wrapped[..header::FILE_KEY_LEN].copy_from_slice(&ciphertext);
+    wrapped[header::FILE_KEY_LEN..].copy_from_slice(&tag);
+    Ok(wrapped)
+}
+
+fn unwrap_file_key(wrap_key: &[u8; 32], ephemeral_key: &[u8], wrapped: &[u8]) -> Option<Vec<u8>> {
+    let (ciphertext, tag) = wrapped.split_at(header::FILE_KEY_LEN);
+    symm::decrypt_aead(
+        AesCipher::aes_256_gcm(),
+        wrap_key,
+        Some(&[0u8; header::AES_GCM_NONCE_LEN]),
+        ephemeral_key,
+        ciphertext,
+        tag,
+    )
+    .ok()
+}
+
+// The signature covers the header, the encrypted payload and the signer key,
+// `hasher` has already seen the context, the header and the payload.
+fn signature_digest(mut hasher: Sha256, curve: Curve, public_key: &[u8]) -> [u8; 32] {
+    hasher.update(&[curve.into()]);
+    hasher.update(public_key);
+    hasher.finish()
+}
+
+#[derive(Clone)]
 pub struct AesECCCipher {
     key: String,
+    recipients: Vec<String>,
+    curve: Option<Curve>,
+    signer: Option<(Curve, String)>,
+    verifier: Option<(Curve, String)>,
 }
 
 impl AesECCCipher {
     pub fn new(key: &str) -> Self {
         AesECCCipher {
             key: key.to_string(),
+            recipients: Vec::new(),
+            curve: None,
+            signer: None,
+            verifier: None,
         }
     }
-}
 
-impl Cipher for AesECCCipher {
-    fn encrypt<R, W>(&self, src: &mut R, dst: &mut W) -> anyhow::Result<()>
-    where
-        R: Read,
-        W: Write,
-    {
-        if self.key.len() != header::ECC_PUBLIC_KEY_LEN * 2 {
-            return Err(anyhow::anyhow!("illegal public key"));
+    // Encrypt for a key on `curve`. When decrypting the curve is read from the
+    // header, setting it here only makes a mismatch an explicit error.
+    pub fn with_curve(key: &str, curve: Curve) -> Self {
+        AesECCCipher {
+            curve: Some(curve),
+            ..AesECCCipher::new(key)
         }
-        let key_pair = micro_uecc_safe::uecc_mkae_key_with_secp256k1()?;
-
-        let mut secret_key_buf = [0u8; 32];
-        let mut server_public_key = utils::decode_hex(&self.key)?;
-        let mut client_private_key = utils::decode_hex(&key_pair.private_key)?;
+    }
 
-        micro_uecc_safe::ucc_shared_secret_whith_secp256k1(
-            &mut server_public_key,
-            &mut client_private_key,
-            &mut secret_key_buf,
-        )?;
+    // Also encrypt for this public key, the file can be decrypted with any one
+    // of the recipients' private keys. All recipients share the cipher's curve.
+    pub fn add_recipient(mut self, public_key: &str) -> Self {
+        self.recipients.push(public_key.to_string());
+        self
+    }
 
-        let iv = utils::generate_random_iv();
-        let secret_key = utils::encode_hex(&secret_key_buf);
-        log::trace!("server_public_key: {}", self.key);
-        log::trace!("client_public_key: {}", key_pair.public_key);
-        log::trace!("client_private_key: {}", key_pair.private_key);
-        log::trace!("secret_key: {}", secret_key);
-        log::trace!("iv: {}", utils::encode_hex(&iv));
+    // Append an ECDSA signature made with the sender's private key when encrypting
+    pub fn sign_with(mut self, private_key: &str, curve: Curve) -> Self {
+        self.signer = Some((curve, private_key.to_string()));
+        self
+    }
 
-        let header = header::AesECCHeader::new(&key_pair.public_key, &iv);
+    // Require a valid signature made by this public key when decrypting
+    pub fn verify_with(mut self, public_key: &str, curve: Curve) -> Self {
+        self.verifier = Some((curve, public_key.to_string()));
+        self
+    }
 
-        let mut header_bytes = [0u8; header::AesECCHeader::BYTE_LEN];
-        header.write_bytes(&mut header_bytes);
-        dst.write(&header_bytes)?;
+    fn check_curve(&self, file_curve: Curve) -> anyhow::Result<()> {
+        match self.curve {
+            Some(curve) if curve != file_curve => Err(anyhow::anyhow!(
+                "The input file was encrypted for a {} key, but a {} key was given",
+                file_curve,
+                curve
+            )),
+            _ => Ok(()),
+        }
+    }
 
-        let cipher = AesCipher::aes_256_ecb();
-        let mut crypter = Crypter::new(cipher, Mode::Encrypt, &secret_key_buf, Some(&iv))?;
-        crypter.pad(true);
+    fn recipient_keys(&self, curve: Curve) -> anyhow::Result<Vec<PublicKey>> {
+        std::iter::once(&self.key)
+            .chain(self.recipients.iter())
+            .map(|key| {
+                if key.len() != header::ECC_PUBLIC_KEY_LEN * 2 {
+                    return Err(anyhow::anyhow!("illegal public key"));
+                }
+                curve.public_key(&utils::decode_hex(key)?)
+            })
+            .collect()
+    }
 
-        let block_size = cipher.block_size();
-        log::trace!("block_size: {}", block_size);
-        let mut buffer = [0u8; BUFFER_SIZE];
-        let mut output_buffer = vec![0u8; BUFFER_SIZE + block_size];
-        let mut total_origin_len = 0;
-        let mut total_encrypt_len = 0;
-        loop {
-            let bytes_read = src.read(&mut buffer)?;
-            if bytes_read == 0 {
-                break;
+    // `derive_wrap_key` gets the shared secret and the recipient's ephemeral
+    // key, it differs between formats.
+    fn find_file_key<F>(
+        &self,
+        curve: Curve,
+        recipients: Vec<&Recipient>,
+        derive_wrap_key: F,
+    ) -> anyhow::Result<Vec<u8>>
+    where
+        F: Fn(&[u8], &[u8]) -> anyhow::Result<[u8; 32]>,
+    {
+        let private_key = curve.private_key(&utils::decode_hex(&self.key)?)?;
+        // A key of the other curve is usually a valid scalar too, so this only
+        // catches some mismatches, the error below names the curve for the rest.
+        private_key.public_key().map_err(|_| {
+            anyhow::anyhow!(
+                "The input file was encrypted for a {} key, the key given is not one",
+                curve
+            )
+        })?;
+        for (index, recipient) in recipients.iter().enumerate() {
+            // one malformed stanza must not hide the others
+            let Ok(client_public_key) = curve.public_key(&recipient.ephemeral_key) else {
+                log::debug!("recipient {}: invalid ephemeral key", index);
+                continue;
+            };
+            let secret_key_buf = private_key.shared_secret(&client_public_key)?;
+            let wrap_key = derive_wrap_key(&secret_key_buf, &recipient.ephemeral_key)?;
+            if let Some(file_key) =
+                unwrap_file_key(&wrap_key, &recipient.ephemeral_key, &recipient.wrapped_key)
+            {
+                log::trace!("recipient: {}", index);
+                log::trace!("client_public_key: {}", client_public_key.to_hex());
+                return Ok(file_key);
             }
-            total_origin_len += bytes_read;
-            let result = crypter.update(&buffer[..bytes_read], &mut output_buffer[0..])?;
-            total_encrypt_len += result;
-            dst.write_all(&output_buffer[..result])?;
         }
-
-        let result = crypter.finalize(&mut output_buffer[0..])?;
-        total_encrypt_len += result;
-        dst.write_all(&output_buffer[..result])?;
-        log::trace!("total_origin_len: {}", total_origin_len);
-        log::trace!("total_encrypt_len: {}", total_encrypt_len);
-
-        dst.flush()?;
-
-        Ok(())
+        match self.curve {
+            Some(_) => Err(anyhow::anyhow!(
+                "The key is not one of the {} recipients of the input file",
+                recipients.len()
+            )),
+            None => Err(anyhow::anyhow!(
+                "The key is not one of the {} recipients of the input file, which is for {} keys",
+                recipients.len(),
+                curve
+            )),
+        }
     }
 
-    fn decrypt<R, W>(&self, src: &mut R, dst: &mut W) -> anyhow::Result<()>
+    fn decrypt_v2<R, W>(&self, header_bytes: &[u8], src: &mut R, dst: &mut W) -> anyhow::Result<()>
     where
         R: Read,
         W: Write,
     {
-        if self.key.len() != header::ECC_PRIVATE_KEY_LEN * 2 {
-            return Err(anyhow::anyhow!("illegal private key"));
-        }
-
-        let mut header_bytes = [0u8; header::AesECCHeader::BYTE_LEN];
-        src.read_exact(&mut header_bytes)?;
-        let header = header::AesECCHeader::try_from(&header_bytes[0..])?;
-        let version =
-            version::Version::try_from(header.format()).map_err(|err| anyhow::anyhow!(err))?;
-        if version != version::Version::V2 {
-            return anyhow::Result::Err(anyhow::anyhow!("Only v2 is supported"));
-        }
-
-        let mut secret_key_buf = [0u8; 32];
-        let mut client_public_key = [0u8; header::ECC_PUBLIC_KEY_LEN];
-        client_public_key.copy_from_slice(header.key_bytes());
-        let mut server_private_key = utils::decode_hex(&self.key)?;
+        let header = header::AesECCHeader::try_from(header_bytes)?;
+        self.check_curve(Curve::Secp256k1)?;
 
-        micro_uecc_safe::ucc_shared_secret_whith_secp256k1(
-            &mut client_public_key,
-            &mut server_private_key,
-            &mut secret_key_buf,
-        )?;
+        let client_public_key = header.key_bytes();
+        let server_private_key = utils::decode_hex(&self.key)?;
+        let secret_key_buf =
+            Curve::Secp256k1.shared_secret(client_public_key, &server_private_key)?;
 
         let iv_buf = header.iv_bytes();
         let secret_key = utils::encode_hex(&secret_key_buf);
         log::trace!("server_private_key: {}", self.key);
         log::trace!(
             "client_public_key: {}",
-            utils::encode_hex(&client_public_key)
+            utils::encode_hex(client_public_key)
         );
         log::trace!("secret_key: {}", secret_key);
         log::trace!("iv: {}", utils::encode_hex(iv_buf));
@@ -161,4 +297,950 @@ impl Cipher for AesECCCipher {
 
         Ok(())
     }
+
+    fn decrypt_v3<R, W>(
+        &self,
+        header_bytes: &[u8],
+        src: &mut R,
+        dst: &mut W,
+    ) -> anyhow::Result<Option<Metadata>>
+    where
+        R: Read,
+        W: Write,
+    {
+        let header = header::AesECCGcmHeader::try_from(header_bytes)?;
+        let curve = header.curve();
+        self.check_curve(curve)?;
+        log::trace!("curve: {}", curve);
+
+        let mut recipients = vec![0u8; header.recipients_len()];
+        src.read_exact(&mut recipients)?;
+        log::trace!("recipients: {}", header.recipients());
+        let stanzas: Vec<Recipient> = recipients
+            .chunks_exact(header::RecipientStanza::BYTE_LEN)
+            .map(|bytes| Recipient::from_stanza(&header::RecipientStanza::read_bytes(bytes)))
+            .collect();
+
+        log::trace!("server_private_key: {}", self.key);
+        let file_key =
+            self.find_file_key(curve, stanzas.iter().collect(), |secret, ephemeral| {
+                derive_wrap_key(secret, &header, ephemeral)
+            })?;
+        let (aes_key, nonce) = derive_v3_keys(&file_key, &header, &recipients)?;
+        log::trace!("salt: {}", utils::encode_hex(header.salt_bytes()));
+        log::trace!("nonce: {}", utils::encode_hex(&nonce));
+
+        let chunk_size = header.chunk_size();
+        stream::check_chunk_size(chunk_size)?;
+        log::trace!("chunk_size: {}", chunk_size);
+
+        let mut hasher = Sha256::new();
+        hasher.update(V3_SIGNATURE_CONTEXT);
+        hasher.update(header_bytes);
+        hasher.update(&recipients);
+        let payload = PayloadDecryptor::new(
+            StreamDecryptor::new(aes_key, nonce, chunk_size),
+            hasher,
+            header.flags() & header::FLAG_SIGNED != 0,
+            header.flags() & header::FLAG_METADATA != 0,
+            self.verifier.clone(),
+        )?;
+        decrypt_payload(payload, src, dst)
+    }
+
+    // Unwraps the file key of a format 5 header
+    fn v5_payload(
+        &self,
+        header: &ExtensibleHeader,
+        header_bytes: &[u8],
+    ) -> anyhow::Result<PayloadDecryptor> {
+        if header.algorithm != Algorithm::Ecies {
+            return Err(anyhow::anyhow!(
+                "The input file was encrypted with a passphrase"
+            ));
+        }
+        let curve = header
+            .curve()
+            .ok_or_else(|| anyhow::anyhow!("The header has no curve"))?;
+        self.check_curve(curve)?;
+        log::trace!("curve: {}", curve);
+
+        log::trace!("server_private_key: {}", self.key);
+        let file_key = self.find_file_key(curve, header.recipients(), |secret, ephemeral| {
+            derive_v5_wrap_key(secret, &header.salt, ephemeral)
+        })?;
+        let (aes_key, nonce) = header.payload_keys(&file_key)?;
+        log::trace!("salt: {}", utils::encode_hex(&header.salt));
+        log::trace!("nonce: {}", utils::encode_hex(&nonce));
+
+        stream::check_chunk_size(header.chunk_size)?;
+        log::trace!("chunk_size: {}", header.chunk_size);
+
+        let mut hasher = Sha256::new();
+        hasher.update(V5_SIGNATURE_CONTEXT);
+        hasher.update(header_bytes);
+        PayloadDecryptor::new(
+            StreamDecryptor::new(aes_key, nonce, header.chunk_size).with_aad(header_bytes),
+            hasher,
+            header.signed(),
+            header.metadata(),
+            self.verifier.clone(),
+        )
+    }
+
+    // Everything up to the payload of a format 5 file: the header with the
+    // file key wrapped for every recipient, and the metadata record
+    fn start_v5(&self, metadata: Option<&Metadata>) -> anyhow::Result<Encryptor> {
+        let curve = self.curve.unwrap_or_default();
+        let server_public_keys = self.recipient_keys(curve)?;
+        if server_public_keys.len() > header::MAX_RECIPIENTS as usize {
+            return Err(anyhow::anyhow!(
+                "Too many recipients, at most {} are supported",
+                header::MAX_RECIPIENTS
+            ));
+        }
+        let signer = match &self.signer {
+            Some((signer_curve, private_key)) => {
+                let private_key = signer_curve.private_key(&utils::decode_hex(private_key)?)?;
+                let public_key = private_key.public_key()?;
+                Some((*signer_curve, private_key, public_key))
+            }
+            None => None,
+        };
+
+        let salt = utils::generate_random_bytes::<{ header::HKDF_SALT_LEN }>();
+        let mut header = ExtensibleHeader::new(Algorithm::Ecies, stream::DEFAULT_CHUNK_SIZE, salt);
+        header.extensions.push(Extension::Curve(curve));
+        log::trace!("curve: {}", curve);
+        log::trace!("recipients: {}", server_public_keys.len());
+        log::trace!("salt: {}", utils::encode_hex(&salt));
+
+        let file_key = utils::generate_random_bytes::<{ header::FILE_KEY_LEN }>();
+        for server_public_key in &server_public_keys {
+            let key_pair = curve.make_key()?;
+            let secret_key_buf = key_pair.private_key.shared_secret(server_public_key)?;
+            let client_public_key = key_pair.public_key.as_bytes();
+            let wrap_key = derive_v5_wrap_key(&secret_key_buf, &salt, client_public_key)?;
+            let mut recipient = Recipient {
+                ephemeral_key: [0u8; header::ECC_PUBLIC_KEY_LEN],
+                wrapped_key: wrap_file_key(&wrap_key, client_public_key, &file_key)?,
+            };
+            recipient.ephemeral_key.copy_from_slice(client_public_key);
+            header.extensions.push(Extension::Recipient(recipient));
+            log::trace!("server_public_key: {}", server_public_key.to_hex());
+            log::trace!("client_public_key: {}", key_pair.public_key.to_hex());
+        }
+        if signer.is_some() {
+            header.extensions.push(Extension::Signed);
+        }
+        if metadata.is_some() {
+            header.extensions.push(Extension::Metadata);
+        }
+
+        let header_bytes = header.to_bytes()?;
+        let (aes_key, nonce) = header.payload_keys(&file_key)?;
+        log::trace!("nonce: {}", utils::encode_hex(&nonce));
+        let mut hasher = Sha256::new();
+        hasher.update(V5_SIGNATURE_CONTEXT);
+        hasher.update(&header_bytes);
+
+        let mut stream = StreamEncryptor::new(aes_key, nonce, stream::DEFAULT_CHUNK_SIZE)
+            .with_aad(&header_bytes);
+        let mut pending = Vec::new();
+        if let Some(metadata) = metadata {
+            stream.update(&metadata.to_bytes()?, &mut pending)?;
+        }
+        Ok(Encryptor {
+            header: Some(header_bytes),
+            stream,
+            hasher,
+            signer,
+            pending,
+        })
+    }
+}
+
+impl AesECCCipher {
+    // Push-based encryption to format 5, see Encryptor
+    pub fn encryptor(&self) -> anyhow::Result<Encryptor> {
+        self.start_v5(None)
+    }
+
+    pub fn encryptor_with_metadata(&self, metadata: &Metadata) -> anyhow::Result<Encryptor> {
+        self.start_v5(Some(metadata))
+    }
+
+    // Push-based decryption of format 5. The signer is only checked at the
+    // end, see Decryptor
+    pub fn decryptor(&self) -> anyhow::Result<Decryptor> {
+        if self.key.len() != header::ECC_PRIVATE_KEY_LEN * 2 {
+            return Err(anyhow::anyhow!("illegal private key"));
+        }
+        Ok(Decryptor {
+            cipher: self.clone(),
+            header_bytes: Vec::new(),
+            header: None,
+            payload: None,
+        })
+    }
+
+    fn encrypt_v5<R, W>(
+        &self,
+        metadata: Option<&Metadata>,
+        src: &mut R,
+        dst: &mut W,
+    ) -> anyhow::Result<()>
+    where
+        R: Read,
+        W: Write,
+    {
+        let mut encryptor = self.start_v5(metadata)?;
+        dst.write_all(&encryptor.header()?)?;
+        stream::pump(encryptor, src, dst)
+    }
+
+    fn decrypt_any<R, W>(&self, src: &mut R, dst: &mut W) -> anyhow::Result<Option<Metadata>>
+    where
+        R: Read,
+        W: Write,
+    {
+        if self.key.len() != header::ECC_PRIVATE_KEY_LEN * 2 {
+            return Err(anyhow::anyhow!("illegal private key"));
+        }
+
+        let mut prefix = [0u8; header::FORMAT_PREFIX_LEN];
+        src.read_exact(&mut prefix)?;
+        let version = header::read_format(&prefix)?;
+        let header_len = match version {
+            Version::V2 => header::AesECCHeader::BYTE_LEN,
+            Version::V3 => header::AesECCGcmHeader::BYTE_LEN,
+            Version::V5 => {
+                let (header, header_bytes) = format::read_extensible_header(&prefix, src)?;
+                let payload = self.v5_payload(&header, &header_bytes)?;
+                return decrypt_payload(payload, src, dst);
+            }
+            _ => return Err(anyhow::anyhow!("Only v2, v3 and v5 are supported")),
+        };
+
+        let mut header_bytes = vec![0u8; header_len];
+        header_bytes[..header::FORMAT_PREFIX_LEN].copy_from_slice(&prefix);
+        src.read_exact(&mut header_bytes[header::FORMAT_PREFIX_LEN..])?;
+
+        match version {
+            Version::V2 => self.decrypt_v2(&header_bytes, src, dst).map(|_| None),
+            _ => self.decrypt_v3(&header_bytes, src, dst),
+        }
+    }
+}
+
+impl Cipher for AesECCCipher {
+    fn encrypt<R, W>(&self, src: &mut R, dst: &mut W) -> anyhow::Result<()>
+    where
+        R: Read,
+        W: Write,
+    {
+        self.encrypt_v5(None, src, dst)
+    }
+
+    fn decrypt<R, W>(&self, src: &mut R, dst: &mut W) -> anyhow::Result<()>
+    where
+        R: Read,
+        W: Write,
+    {
+        self.decrypt_any(src, dst).map(|_| ())
+    }
+
+    fn supports_metadata(&self) -> bool {
+        true
+    }
+
+    fn encrypt_with_metadata<R, W>(
+        &self,
+        metadata: &Metadata,
+        src: &mut R,
+        dst: &mut W,
+    ) -> anyhow::Result<()>
+    where
+        R: Read,
+        W: Write,
+    {
+        self.encrypt_v5(Some(metadata), src, dst)
+    }
+
+    fn decrypt_with_metadata<R, W>(
+        &self,
+        src: &mut R,
+        dst: &mut W,
+    ) -> anyhow::Result<Option<Metadata>>
+    where
+        R: Read,
+        W: Write,
+    {
+        self.decrypt_any(src, dst)
+    }
+}
+
+fn check_signature(
+    verifier: Option<&(Curve, String)>,
+    hasher: Sha256,
+    trailer: &[u8],
+) -> anyhow::Result<()> {
+    let signer_curve = Curve::try_from(trailer[0]).map_err(|err| anyhow::anyhow!(err))?;
+    let (signer_key, signature) = trailer[1..].split_at(header::ECC_PUBLIC_KEY_LEN);
+    let signer_public_key = signer_curve.public_key(signer_key)?;
+    let digest = signature_digest(hasher, signer_curve, signer_key);
+    if !signer_public_key.verify(&digest, signature)? {
+        return Err(anyhow::anyhow!(
+            "The signature of the input file is invalid"
+        ));
+    }
+
+    match verifier {
+        Some((curve, public_key)) => {
+            if *curve != signer_curve
+                || !public_key.eq_ignore_ascii_case(&signer_public_key.to_hex())
+            {
+                return Err(anyhow::anyhow!(
+                    "The input file is signed by a different key: {} {}",
+                    signer_curve,
+                    signer_public_key.to_hex()
+                ));
+            }
+            log::info!("signature verified: {} {}", signer_curve, public_key);
+        }
+        None => log::warn!(
+            "The input file is signed by {} {}, but no key to verify the signer was given",
+            signer_curve,
+            signer_public_key.to_hex()
+        ),
+    }
+    Ok(())
+}
+
+// The payload of a format 3 or 5 file, fed in pieces of any size. Each chunk
+// is authenticated before it is returned, so callers never see
+// unauthenticated plaintext, but the signer is only checked by finalize. The
+// signature trailer is held back from the stream decryptor and the metadata
+// record is taken off the front.
+struct PayloadDecryptor {
+    stream: StreamDecryptor,
+    // has already seen the signature context and the header
+    hasher: Sha256,
+    verifier: Option<(Curve, String)>,
+    trailer_len: usize,
+    pending: Vec<u8>,
+    plaintext: MetadataSplitter<Vec<u8>>,
+}
+
+impl PayloadDecryptor {
+    fn new(
+        stream: StreamDecryptor,
+        hasher: Sha256,
+        signed: bool,
+        has_metadata: bool,
+        verifier: Option<(Curve, String)>,
+    ) -> anyhow::Result<Self> {
+        if !signed && verifier.is_some() {
+            return Err(anyhow::anyhow!("The input file is not signed"));
+        }
+        Ok(PayloadDecryptor {
+            stream,
+            hasher,
+            verifier,
+            trailer_len: if signed {
+                header::SIGNATURE_TRAILER_LEN
+            } else {
+                0
+            },
+            pending: Vec::new(),
+            plaintext: MetadataSplitter::new(Vec::new(), has_metadata),
+        })
+    }
+}
+
+impl Transform for PayloadDecryptor {
+    type Output = Option<Metadata>;
+
+    fn update_into(&mut self, data: &[u8], out: &mut Vec<u8>) -> anyhow::Result<()> {
+        self.pending.extend_from_slice(data);
+        if self.pending.len() <= self.trailer_len {
+            return Ok(());
+        }
+        let ready = self.pending.len() - self.trailer_len;
+        self.hasher.update(&self.pending[..ready]);
+        let mut decrypted = Vec::new();
+        self.stream.update(&self.pending[..ready], &mut decrypted)?;
+        self.pending.drain(..ready);
+        self.plaintext.write_all(&decrypted)?;
+        out.append(self.plaintext.get_mut());
+        Ok(())
+    }
+
+    fn finalize_into(mut self, out: &mut Vec<u8>) -> anyhow::Result<Option<Metadata>> {
+        if self.pending.len() != self.trailer_len {
+            return Err(anyhow::anyhow!("The input file is truncated"));
+        }
+        let mut decrypted = Vec::new();
+        self.stream.finalize(&mut decrypted)?;
+        if self.trailer_len != 0 {
+            check_signature(self.verifier.as_ref(), self.hasher, &self.pending)?;
+        }
+        self.plaintext.write_all(&decrypted)?;
+        out.append(self.plaintext.get_mut());
+        self.plaintext.finish()
+    }
+}
+
+// With a verifier, plaintext from anyone but the expected signer must never
+// reach `dst`. The ciphertext is spooled to a temporary file until its
+// signature checks out and only then decrypted, which checks it once more.
+fn decrypt_payload<R, W>(
+    payload: PayloadDecryptor,
+    src: &mut R,
+    dst: &mut W,
+) -> anyhow::Result<Option<Metadata>>
+where
+    R: Read,
+    W: Write,
+{
+    if payload.verifier.is_none() {
+        return stream::pump(payload, src, dst);
+    }
+    let mut spool = utils::spool_file()?;
+    let mut hasher = payload.hasher.clone();
+    let mut pending = Vec::new();
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    loop {
+        let bytes_read = src.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        spool.write_all(&buffer[..bytes_read])?;
+        pending.extend_from_slice(&buffer[..bytes_read]);
+        let ready = pending.len().saturating_sub(payload.trailer_len);
+        hasher.update(&pending[..ready]);
+        pending.drain(..ready);
+    }
+    if pending.len() != payload.trailer_len {
+        return Err(anyhow::anyhow!("The input file is truncated"));
+    }
+    check_signature(payload.verifier.as_ref(), hasher, &pending)?;
+    spool.seek(SeekFrom::Start(0))?;
+    stream::pump(payload, &mut BufReader::new(spool), dst)
+}
+
+// Push-based format 5 encryption, for data that arrives in pieces rather
+// than through a Read. Send header() first, then the output of every update
+// and of finalize, in order.
+pub struct Encryptor {
+    header: Option<Vec<u8>>,
+    stream: StreamEncryptor,
+    hasher: Sha256,
+    signer: Option<(Curve, PrivateKey, PublicKey)>,
+    // ciphertext of the metadata record, goes out with the first update
+    pending: Vec<u8>,
+}
+
+impl Encryptor {
+    // The container header, which can only be taken once and has to come
+    // before any payload
+    pub fn header(&mut self) -> anyhow::Result<Vec<u8>> {
+        self.header
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("The header was already emitted"))
+    }
+
+    pub fn update(&mut self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        self.update_into(data, &mut out)?;
+        Ok(out)
+    }
+
+    // Appends the ciphertext of every chunk completed so far to `out`, the
+    // rest of `data` is buffered until the next call
+    pub fn update_into(&mut self, data: &[u8], out: &mut Vec<u8>) -> anyhow::Result<()> {
+        if self.header.is_some() {
+            return Err(anyhow::anyhow!("The header has to be emitted first"));
+        }
+        let start = out.len();
+        out.append(&mut self.pending);
+        self.stream.update(data, out)?;
+        self.hasher.update(&out[start..]);
+        Ok(())
+    }
+
+    pub fn finalize(self) -> anyhow::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        self.finalize_into(&mut out)?;
+        Ok(out)
+    }
+
+    // The last chunk, then the signature trailer when signing
+    pub fn finalize_into(mut self, out: &mut Vec<u8>) -> anyhow::Result<()> {
+        if self.header.is_some() {
+            return Err(anyhow::anyhow!("The header has to be emitted first"));
+        }
+        let start = out.len();
+        out.append(&mut self.pending);
+        self.stream.finalize(out)?;
+        self.hasher.update(&out[start..]);
+
+        if let Some((signer_curve, private_key, public_key)) = self.signer {
+            let digest = signature_digest(self.hasher, signer_curve, public_key.as_bytes());
+            let signature = private_key.sign(&digest)?;
+            out.push(signer_curve.into());
+            out.extend_from_slice(public_key.as_bytes());
+            out.extend_from_slice(&signature);
+            log::trace!("signer_public_key: {}", public_key.to_hex());
+            log::trace!("signature: {}", utils::encode_hex(&signature));
+        }
+        Ok(())
+    }
+}
+
+impl Transform for Encryptor {
+    type Output = ();
+
+    fn update_into(&mut self, data: &[u8], out: &mut Vec<u8>) -> anyhow::Result<()> {
+        Encryptor::update_into(self, data, out)
+    }
+
+    fn finalize_into(self, out: &mut Vec<u8>) -> anyhow::Result<()> {
+        Encryptor::finalize_into(self, out)
+    }
+}
+
+// Push-based format 5 decryption, the counterpart of Encryptor. Feed the
+// input to read_header until header() is Some, then the rest to update.
+//
+// UNVERIFIED SENDER: update returns plaintext as soon as its chunk is
+// authenticated, but a signature, and so the signer required by
+// verify_with, can only be checked by finalize_into at the very end. Until
+// finalize_into returns Ok, treat everything update returned as coming from
+// an unknown sender and discard it on error. Cipher::decrypt holds all output
+// back until the signer is checked, use it when that is not acceptable.
+pub struct Decryptor {
+    cipher: AesECCCipher,
+    header_bytes: Vec<u8>,
+    header: Option<ExtensibleHeader>,
+    payload: Option<PayloadDecryptor>,
+}
+
+impl Decryptor {
+    // Takes header bytes off the front of `data` and returns how many it
+    // used, the rest of `data` is payload. The file key is unwrapped as soon
+    // as the whole header is in.
+    pub fn read_header(&mut self, data: &[u8]) -> anyhow::Result<usize> {
+        if self.header.is_some() {
+            return Ok(0);
+        }
+        let prefix_len = header::ExtensibleHeaderPrefix::BYTE_LEN;
+        let mut used = self.fill(data, prefix_len);
+        if self.header_bytes.len() < prefix_len {
+            return Ok(used);
+        }
+        if header::read_format(&self.header_bytes)? != Version::V5 {
+            return Err(anyhow::anyhow!("Only v5 can be decrypted incrementally"));
+        }
+        let header_len = format::extensible_header_len(&self.header_bytes[..prefix_len])?;
+        used += self.fill(&data[used..], header_len);
+        if self.header_bytes.len() < header_len {
+            return Ok(used);
+        }
+
+        let header = ExtensibleHeader::from_bytes(&self.header_bytes)?;
+        self.payload = Some(self.cipher.v5_payload(&header, &self.header_bytes)?);
+        self.header = Some(header);
+        Ok(used)
+    }
+
+    // Moves bytes from the front of `data` to the header until it is `len` long
+    fn fill(&mut self, data: &[u8], len: usize) -> usize {
+        let take = len.saturating_sub(self.header_bytes.len()).min(data.len());
+        self.header_bytes.extend_from_slice(&data[..take]);
+        take
+    }
+
+    // The parsed header, once read_header has seen all of it
+    pub fn header(&self) -> Option<&ExtensibleHeader> {
+        self.header.as_ref()
+    }
+
+    pub fn update(&mut self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        self.update_into(data, &mut out)?;
+        Ok(out)
+    }
+
+    // Appends the plaintext of every chunk authenticated so far to `out`. Its
+    // sender is not verified yet, see Decryptor
+    pub fn update_into(&mut self, data: &[u8], out: &mut Vec<u8>) -> anyhow::Result<()> {
+        self.payload
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("The header has to be read first"))?
+            .update_into(data, out)
+    }
+
+    pub fn finalize(self) -> anyhow::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        self.finalize_into(&mut out)?;
+        Ok(out)
+    }
+
+    // Checks the signature and the signer, then appends the last chunk.
+    // Returns the metadata record if the file has one
+    pub fn finalize_into(self, out: &mut Vec<u8>) -> anyhow::Result<Option<Metadata>> {
+        self.payload
+            .ok_or_else(|| anyhow::anyhow!("The input file is truncated"))?
+            .finalize_into(out)
+    }
+}
+
+// Reads the header off the front of the input first
+impl Transform for Decryptor {
+    type Output = Option<Metadata>;
+
+    fn update_into(&mut self, data: &[u8], out: &mut Vec<u8>) -> anyhow::Result<()> {
+        let used = self.read_header(data)?;
+        if self.header.is_none() {
+            return Ok(());
+        }
+        Decryptor::update_into(self, &data[used..], out)
+    }
+
+    fn finalize_into(self, out: &mut Vec<u8>) -> anyhow::Result<Option<Metadata>> {
+        Decryptor::finalize_into(self, out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip_key_pair() -> micro_uecc_safe::UEcckeyPair {
+        Curve::Secp256k1.make_key().unwrap().into()
+    }
+
+    // A format 3 file as earlier versions wrote it
+    fn encrypt_v3(public_key: &str, plaintext: &[u8]) -> Vec<u8> {
+        let curve = Curve::Secp256k1;
+        let salt = utils::generate_random_bytes::<{ header::HKDF_SALT_LEN }>();
+        let header = header::AesECCGcmHeader::new(curve, 0, stream::DEFAULT_CHUNK_SIZE, &salt, 1);
+        let mut encrypted = vec![0u8; header::AesECCGcmHeader::BYTE_LEN];
+        header.write_bytes(&mut encrypted);
+
+        let file_key = utils::generate_random_bytes::<{ header::FILE_KEY_LEN }>();
+        let ephemeral = curve.make_key().unwrap();
+        let secret_key_buf = ephemeral
+            .private_key
+            .shared_secret(
+                &curve
+                    .public_key(&utils::decode_hex(public_key).unwrap())
+                    .unwrap(),
+            )
+            .unwrap();
+        let client_public_key = ephemeral.public_key.as_bytes();
+        let wrap_key = derive_wrap_key(&secret_key_buf, &header, client_public_key).unwrap();
+        let wrapped_key = wrap_file_key(&wrap_key, client_public_key, &file_key).unwrap();
+        let mut stanza = [0u8; header::RecipientStanza::BYTE_LEN];
+        header::RecipientStanza::new(client_public_key, &wrapped_key).write_bytes(&mut stanza);
+        encrypted.extend_from_slice(&stanza);
+
+        let (aes_key, nonce) = derive_v3_keys(&file_key, &header, &stanza).unwrap();
+        let mut encryptor = StreamEncryptor::new(aes_key, nonce, stream::DEFAULT_CHUNK_SIZE);
+        encryptor.update(plaintext, &mut encrypted).unwrap();
+        encryptor.finalize(&mut encrypted).unwrap();
+        encrypted
+    }
+
+    #[test]
+    fn encrypt_decrypt_v5() {
+        let pair = roundtrip_key_pair();
+        let plaintext = b"hello file_cipher, hello file_cipher".repeat(500);
+
+        let mut encrypted = Vec::new();
+        AesECCCipher::new(&pair.public_key)
+            .encrypt(&mut &plaintext[..], &mut encrypted)
+            .unwrap();
+        assert_eq!(header::read_format(&encrypted).unwrap(), Version::V5);
+
+        let mut decrypted = Vec::new();
+        AesECCCipher::new(&pair.private_key)
+            .decrypt(&mut &encrypted[..], &mut decrypted)
+            .unwrap();
+        assert_eq!(decrypted, plaintext);
+    }
+
+    #[test]
+    fn encrypt_decrypt_secp256r1() {
+        let pair: micro_uecc_safe::UEcckeyPair = Curve::Secp256r1.make_key().unwrap().into();
+        let plaintext = b"hello secp256r1".repeat(100);
+
+        let mut encrypted = Vec::new();
+        AesECCCipher::with_curve(&pair.public_key, Curve::Secp256r1)
+            .encrypt(&mut &plaintext[..], &mut encrypted)
+            .unwrap();
+
+        let mut decrypted = Vec::new();
+        AesECCCipher::new(&pair.private_key)
+            .decrypt(&mut &encrypted[..], &mut decrypted)
+            .unwrap();
+        assert_eq!(decrypted, plaintext);
+
+        let err = AesECCCipher::with_curve(&pair.private_key, Curve::Secp256k1)
+            .decrypt(&mut &encrypted[..], &mut Vec::new())
+            .unwrap_err();
+        assert!(err.to_string().contains("secp256r1"));
+
+        // without a curve the file's curve is named in the error
+        let other: micro_uecc_safe::UEcckeyPair = Curve::Secp256k1.make_key().unwrap().into();
+        let err = AesECCCipher::new(&other.private_key)
+            .decrypt(&mut &encrypted[..], &mut Vec::new())
+            .unwrap_err();
+        assert!(err.to_string().contains("secp256r1"));
+
+        let result = AesECCCipher::with_curve(&pair.public_key, Curve::Secp256k1)
+            .encrypt(&mut &plaintext[..], &mut Vec::new());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn multiple_recipients() {
+        let first = roundtrip_key_pair();
+        let second = roundtrip_key_pair();
+        let other = roundtrip_key_pair();
+        let plaintext = b"shipped to several teams".repeat(500);
+
+        let mut encrypted = Vec::new();
+        AesECCCipher::new(&first.public_key)
+            .add_recipient(&second.public_key)
+            .encrypt(&mut &plaintext[..], &mut encrypted)
+            .unwrap();
+
+        for pair in [&first, &second] {
+            let mut decrypted = Vec::new();
+            AesECCCipher::new(&pair.private_key)
+                .decrypt(&mut &encrypted[..], &mut decrypted)
+                .unwrap();
+            assert_eq!(decrypted, plaintext);
+        }
+
+        let result =
+            AesECCCipher::new(&other.private_key).decrypt(&mut &encrypted[..], &mut Vec::new());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn signed_v3() {
+        let pair = roundtrip_key_pair();
+        let sender = roundtrip_key_pair();
+        let other = roundtrip_key_pair();
+        let plaintext = b"signed by the sender".repeat(5000);
+
+        let mut encrypted = Vec::new();
+        AesECCCipher::new(&pair.public_key)
+            .sign_with(&sender.private_key, Curve::Secp256k1)
+            .encrypt(&mut &plaintext[..], &mut encrypted)
+            .unwrap();
+
+        let mut decrypted = Vec::new();
+        AesECCCipher::new(&pair.private_key)
+            .verify_with(&sender.public_key, Curve::Secp256k1)
+            .decrypt(&mut &encrypted[..], &mut decrypted)
+            .unwrap();
+        assert_eq!(decrypted, plaintext);
+
+        // the payload spans several chunks, none of them may come out
+        let mut decrypted = Vec::new();
+        let result = AesECCCipher::new(&pair.private_key)
+            .verify_with(&other.public_key, Curve::Secp256k1)
+            .decrypt(&mut &encrypted[..], &mut decrypted);
+        assert!(result.is_err());
+        assert!(decrypted.is_empty());
+
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 0x01;
+        let result =
+            AesECCCipher::new(&pair.private_key).decrypt(&mut &encrypted[..], &mut Vec::new());
+        assert!(result.is_err());
+
+        let mut unsigned = Vec::new();
+        AesECCCipher::new(&pair.public_key)
+            .encrypt(&mut &plaintext[..], &mut unsigned)
+            .unwrap();
+        let result = AesECCCipher::new(&pair.private_key)
+            .verify_with(&sender.public_key, Curve::Secp256k1)
+            .decrypt(&mut &unsigned[..], &mut Vec::new());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn metadata_roundtrip() {
+        let pair = roundtrip_key_pair();
+        let plaintext = b"named content".repeat(10000);
+        let metadata = Metadata::with_name("notes.txt");
+
+        let mut encrypted = Vec::new();
+        AesECCCipher::new(&pair.public_key)
+            .encrypt_with_metadata(&metadata, &mut &plaintext[..], &mut encrypted)
+            .unwrap();
+
+        let cipher = AesECCCipher::new(&pair.private_key);
+        let mut decrypted = Vec::new();
+        let restored = cipher
+            .decrypt_with_metadata(&mut &encrypted[..], &mut decrypted)
+            .unwrap();
+        assert_eq!(restored, Some(metadata));
+        assert_eq!(decrypted, plaintext);
+
+        // plain decrypt drops the record
+        let mut decrypted = Vec::new();
+        cipher.decrypt(&mut &encrypted[..], &mut decrypted).unwrap();
+        assert_eq!(decrypted, plaintext);
+    }
+
+    #[test]
+    fn decrypt_legacy_v2() {
+        let pair = roundtrip_key_pair();
+        let plaintext = b"archived with file_cipher v2".repeat(100);
+
+        let ephemeral = roundtrip_key_pair();
+        let secret_key_buf = Curve::Secp256k1
+            .shared_secret(
+                &utils::decode_hex(&pair.public_key).unwrap(),
+                &utils::decode_hex(&ephemeral.private_key).unwrap(),
+            )
+            .unwrap();
+        let iv = utils::generate_random_bytes::<16>();
+        let header =
+            header::AesECCHeader::new(&utils::decode_hex(&ephemeral.public_key).unwrap(), &iv);
+        let mut encrypted = vec![0u8; header::AesECCHeader::BYTE_LEN];
+        header.write_bytes(&mut encrypted);
+        encrypted.extend(
+            openssl::symm::encrypt(AesCipher::aes_256_ecb(), &secret_key_buf, None, &plaintext)
+                .unwrap(),
+        );
+
+        let mut decrypted = Vec::new();
+        AesECCCipher::new(&pair.private_key)
+            .decrypt(&mut &encrypted[..], &mut decrypted)
+            .unwrap();
+        assert_eq!(decrypted, plaintext);
+    }
+
+    #[test]
+    fn decrypt_legacy_v3() {
+        let pair = roundtrip_key_pair();
+        let plaintext = b"archived with file_cipher v3".repeat(5000);
+        let mut encrypted = encrypt_v3(&pair.public_key, &plaintext);
+
+        let mut decrypted = Vec::new();
+        AesECCCipher::new(&pair.private_key)
+            .decrypt(&mut &encrypted[..], &mut decrypted)
+            .unwrap();
+        assert_eq!(decrypted, plaintext);
+
+        let index = header::AesECCGcmHeader::BYTE_LEN + header::RecipientStanza::BYTE_LEN + 10;
+        encrypted[index] ^= 0x01;
+        let mut decrypted = Vec::new();
+        let result =
+            AesECCCipher::new(&pair.private_key).decrypt(&mut &encrypted[..], &mut decrypted);
+        assert!(result.is_err());
+        assert!(decrypted.is_empty());
+    }
+
+    #[test]
+    fn altered_header_fails() {
+        let pair = roundtrip_key_pair();
+        let plaintext = b"hello file_cipher".repeat(100);
+
+        let mut encrypted = Vec::new();
+        AesECCCipher::new(&pair.public_key)
+            .encrypt(&mut &plaintext[..], &mut encrypted)
+            .unwrap();
+        let prefix = &encrypted[..header::FORMAT_PREFIX_LEN];
+        let mut src = &encrypted[header::FORMAT_PREFIX_LEN..];
+        let (mut header, _) = format::read_extensible_header(prefix, &mut src).unwrap();
+
+        // an extension the reader would otherwise skip
+        header.extensions.push(Extension::Unknown {
+            kind: 0x0042,
+            value: b"added later".to_vec(),
+        });
+        let mut altered = header.to_bytes().unwrap();
+        altered.extend_from_slice(src);
+
+        let mut decrypted = Vec::new();
+        let result =
+            AesECCCipher::new(&pair.private_key).decrypt(&mut &altered[..], &mut decrypted);
+        assert!(result.is_err());
+        assert!(decrypted.is_empty());
+    }
+
+    #[test]
+    fn altered_payload_fails() {
+        let pair = roundtrip_key_pair();
+        let plaintext = b"hello file_cipher".repeat(10000);
+
+        let mut encrypted = Vec::new();
+        AesECCCipher::new(&pair.public_key)
+            .encrypt(&mut &plaintext[..], &mut encrypted)
+            .unwrap();
+        let prefix = &encrypted[..header::FORMAT_PREFIX_LEN];
+        let mut src = &encrypted[header::FORMAT_PREFIX_LEN..];
+        let (_, header_bytes) = format::read_extensible_header(prefix, &mut src).unwrap();
+
+        // a bit in the first chunk, nothing may be written
+        let mut altered = encrypted.clone();
+        altered[header_bytes.len() + 10] ^= 1;
+        let mut decrypted = Vec::new();
+        let result =
+            AesECCCipher::new(&pair.private_key).decrypt(&mut &altered[..], &mut decrypted);
+        assert!(result.is_err());
+        assert!(decrypted.is_empty());
+    }
+
+    #[test]
+    fn incremental_roundtrip() {
+        let pair = roundtrip_key_pair();
+        let sender = roundtrip_key_pair();
+        let plaintext = b"arrives in callbacks".repeat(10000);
+        let metadata = Metadata::with_name("stream.bin");
+
+        let mut encryptor = AesECCCipher::new(&pair.public_key)
+            .sign_with(&sender.private_key, Curve::Secp256k1)
+            .encryptor_with_metadata(&metadata)
+            .unwrap();
+        assert!(encryptor.update(b"before the header").is_err());
+        let mut encrypted = encryptor.header().unwrap();
+        for piece in plaintext.chunks(7777) {
+            encryptor.update_into(piece, &mut encrypted).unwrap();
+        }
+        encrypted.extend(encryptor.finalize().unwrap());
+
+        let cipher =
+            AesECCCipher::new(&pair.private_key).verify_with(&sender.public_key, Curve::Secp256k1);
+        let mut decrypted = Vec::new();
+        let restored = cipher
+            .decrypt_with_metadata(&mut &encrypted[..], &mut decrypted)
+            .unwrap();
+        assert_eq!(restored, Some(metadata.clone()));
+        assert_eq!(decrypted, plaintext);
+
+        // small pieces split the header too
+        let mut decryptor = cipher.decryptor().unwrap();
+        let mut decrypted = Vec::new();
+        for piece in encrypted.chunks(100) {
+            let used = decryptor.read_header(piece).unwrap();
+            if decryptor.header().is_some() {
+                decryptor
+                    .update_into(&piece[used..], &mut decrypted)
+                    .unwrap();
+            }
+        }
+        assert!(decryptor.header().unwrap().signed());
+        let restored = decryptor.finalize_into(&mut decrypted).unwrap();
+        assert_eq!(restored, Some(metadata));
+        assert_eq!(decrypted, plaintext);
+    }
 }
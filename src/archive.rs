@@ -0,0 +1,257 @@
+use std::io::{self, PipeReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::cipher::Cipher;
+use crate::utils;
+
+// A whole directory packed into a tar stream and encrypted as one container,
+// so names, sizes and layout are only visible to the key holders.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveEntry {
+    pub path: PathBuf,
+    pub size: u64,
+    pub mode: u32,
+    pub mtime: u64,
+    pub is_dir: bool,
+}
+
+// The tar side runs on its own thread, if the cipher stopped because the other
+// end went away the tar error is the one worth reporting.
+fn is_broken_pipe(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<io::Error>()
+        .is_some_and(|err| err.kind() == io::ErrorKind::BrokenPipe)
+}
+
+pub fn create<C, W>(cipher: &C, dir: &Path, dst: &mut W) -> anyhow::Result<()>
+where
+    C: Cipher,
+    W: Write,
+{
+    if !dir.is_dir() {
+        return Err(anyhow::anyhow!("{} is not a directory", dir.display()));
+    }
+    let name = dir
+        .canonicalize()?
+        .file_name()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("root"));
+
+    let (reader, writer) = io::pipe()?;
+    std::thread::scope(|scope| {
+        let packer = scope.spawn(move || -> anyhow::Result<()> {
+            let mut builder = tar::Builder::new(writer);
+            builder.append_dir_all(&name, dir)?;
+            builder.into_inner()?;
+            Ok(())
+        });
+
+        let encrypted = {
+            let mut reader = reader;
+            cipher.encrypt(&mut reader, dst)
+        };
+        let packed = packer
+            .join()
+            .map_err(|_| anyhow::anyhow!("The archive thread panicked"))?;
+        match encrypted {
+            Err(err) if !is_broken_pipe(&err) => Err(err),
+            // a packer error leaves a truncated but well formed container behind
+            _ => packed,
+        }
+    })
+}
+
+fn read<C, R, T, F>(cipher: &C, src: &mut R, f: F) -> anyhow::Result<T>
+where
+    C: Cipher,
+    R: Read,
+    T: Send,
+    F: FnOnce(&mut tar::Archive<PipeReader>) -> anyhow::Result<T> + Send,
+{
+    let (reader, writer) = io::pipe()?;
+    std::thread::scope(|scope| {
+        let unpacker = scope.spawn(move || -> anyhow::Result<T> {
+            let mut archive = tar::Archive::new(reader);
+            let result = f(&mut archive)?;
+            // let the cipher run to the end, a signature is only checked there
+            io::copy(&mut archive.into_inner(), &mut io::sink())?;
+            Ok(result)
+        });
+
+        let decrypted = {
+            let mut writer = writer;
+            cipher.decrypt(src, &mut writer)
+        };
+        let unpacked = unpacker
+            .join()
+            .map_err(|_| anyhow::anyhow!("The archive thread panicked"))?;
+        match decrypted {
+            Err(err) if !is_broken_pipe(&err) => Err(err),
+            Err(err) => unpacked.and(Err(err)),
+            Ok(()) => unpacked,
+        }
+    })
+}
+
+pub fn list<C, R>(cipher: &C, src: &mut R) -> anyhow::Result<Vec<ArchiveEntry>>
+where
+    C: Cipher,
+    R: Read,
+{
+    read(cipher, src, |archive| {
+        let mut entries = Vec::new();
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let header = entry.header();
+            entries.push(ArchiveEntry {
+                path: entry.path()?.into_owned(),
+                size: header.size()?,
+                mode: header.mode()?,
+                mtime: header.mtime()?,
+                is_dir: header.entry_type().is_dir(),
+            });
+        }
+        Ok(entries)
+    })
+}
+
+// Removes the staging directory of an extraction that did not complete
+struct Staging(PathBuf);
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// Moves everything in `from` into `to`, merging into directories that already
+// exist and replacing files
+fn move_into(from: &Path, to: &Path) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        let is_dir = std::fs::symlink_metadata(&target).is_ok_and(|target| target.is_dir());
+        if is_dir && entry.file_type()?.is_dir() {
+            move_into(&entry.path(), &target)?;
+        } else {
+            std::fs::rename(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+// Extract everything, or only `only` and what is below it, into `output`.
+// Returns the number of extracted entries. Entries are unpacked into a staging
+// directory inside `output` and only moved into place once the whole archive,
+// and its signature, checked out. Permissions are restored without the
+// setuid, setgid and sticky bits.
+pub fn extract<C, R>(
+    cipher: &C,
+    src: &mut R,
+    output: &Path,
+    only: Option<&Path>,
+) -> anyhow::Result<usize>
+where
+    C: Cipher,
+    R: Read,
+{
+    std::fs::create_dir_all(output)?;
+    let staging = Staging(output.join(format!(
+        ".file_cipher-extract-{}",
+        utils::encode_hex(&utils::generate_random_bytes::<8>())
+    )));
+    std::fs::create_dir(&staging.0)?;
+    let extracted = read(cipher, src, |archive| {
+        archive.set_preserve_permissions(false);
+        archive.set_preserve_mtime(true);
+        let mut extracted = 0;
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            if only.is_some_and(|only| !path.starts_with(only)) {
+                continue;
+            }
+            // unpack_in refuses paths that would escape the staging directory
+            if entry.unpack_in(&staging.0)? {
+                log::info!("extract: {}", path.display());
+                extracted += 1;
+            } else {
+                log::warn!("skipped unsafe path: {}", path.display());
+            }
+        }
+        Ok(extracted)
+    })?;
+
+    if let Some(only) = only {
+        if extracted == 0 {
+            return Err(anyhow::anyhow!(
+                "{} was not found in the archive",
+                only.display()
+            ));
+        }
+    }
+    move_into(&staging.0, output)?;
+    Ok(extracted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aes_ecc::AesECCCipher;
+    use crate::curve::Curve;
+
+    #[test]
+    fn create_list_extract() {
+        let root = std::env::temp_dir().join(format!("file_cipher_archive_{}", std::process::id()));
+        let source = root.join("project");
+        std::fs::create_dir_all(source.join("src")).unwrap();
+        std::fs::write(source.join("README"), b"readme").unwrap();
+        std::fs::write(source.join("src/main.rs"), b"fn main() {}").unwrap();
+        // spans several chunks, so damage at the end is found after the rest
+        std::fs::write(source.join("data"), vec![7u8; 200_000]).unwrap();
+
+        let pair: micro_uecc_safe::UEcckeyPair = Curve::Secp256k1.make_key().unwrap().into();
+        let mut encrypted = Vec::new();
+        create(
+            &AesECCCipher::new(&pair.public_key),
+            &source,
+            &mut encrypted,
+        )
+        .unwrap();
+        assert!(!encrypted.windows(7).any(|window| window == b"main.rs"));
+
+        let cipher = AesECCCipher::new(&pair.private_key);
+        let entries = list(&cipher, &mut &encrypted[..]).unwrap();
+        let paths: Vec<_> = entries.iter().map(|entry| entry.path.clone()).collect();
+        assert!(paths.contains(&PathBuf::from("project/src/main.rs")));
+
+        let output = root.join("out");
+        let only = Path::new("project/src");
+        assert_eq!(
+            extract(&cipher, &mut &encrypted[..], &output, Some(only)).unwrap(),
+            2
+        );
+        assert_eq!(
+            std::fs::read(output.join("project/src/main.rs")).unwrap(),
+            b"fn main() {}"
+        );
+        assert!(!output.join("project/README").exists());
+
+        let missing = extract(
+            &cipher,
+            &mut &encrypted[..],
+            &output,
+            Some(Path::new("nope")),
+        );
+        assert!(missing.is_err());
+
+        let mut damaged = encrypted.clone();
+        let last = damaged.len() - 1;
+        damaged[last] ^= 1;
+        let failed = root.join("failed");
+        assert!(extract(&cipher, &mut &damaged[..], &failed, None).is_err());
+        assert_eq!(std::fs::read_dir(&failed).unwrap().count(), 0);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,4 +1,11 @@
+use std::collections::BTreeMap;
 use std::io::{Read, Write};
+
+use crate::aes_ecc::AesECCCipher;
+use crate::format::Algorithm;
+use crate::metadata::Metadata;
+use crate::password::PasswordCipher;
+
 pub trait Cipher {
     fn encrypt<R, W>(&self, src: &mut R, dst: &mut W) -> anyhow::Result<()>
     where
@@ -9,4 +16,192 @@ pub trait Cipher {
     where
         R: Read,
         W: Write;
+
+    // Formats that can carry a metadata record return true here and override
+    // the two methods below
+    fn supports_metadata(&self) -> bool {
+        false
+    }
+
+    fn encrypt_with_metadata<R, W>(
+        &self,
+        metadata: &Metadata,
+        src: &mut R,
+        dst: &mut W,
+    ) -> anyhow::Result<()>
+    where
+        R: Read,
+        W: Write,
+    {
+        let _ = (metadata, src, dst);
+        Err(anyhow::anyhow!("This format cannot store file metadata"))
+    }
+
+    fn decrypt_with_metadata<R, W>(
+        &self,
+        src: &mut R,
+        dst: &mut W,
+    ) -> anyhow::Result<Option<Metadata>>
+    where
+        R: Read,
+        W: Write,
+    {
+        self.decrypt(src, dst).map(|_| None)
+    }
+}
+
+// Object-safe form of Cipher, for picking the cipher at runtime. Every Cipher
+// that can be shared between threads is a DynCipher. The methods have a _dyn
+// suffix so both traits can be imported next to each other.
+pub trait DynCipher: Send + Sync {
+    fn encrypt_dyn(&self, src: &mut dyn Read, dst: &mut dyn Write) -> anyhow::Result<()>;
+
+    fn decrypt_dyn(&self, src: &mut dyn Read, dst: &mut dyn Write) -> anyhow::Result<()>;
+
+    fn supports_metadata_dyn(&self) -> bool;
+
+    fn encrypt_with_metadata_dyn(
+        &self,
+        metadata: &Metadata,
+        src: &mut dyn Read,
+        dst: &mut dyn Write,
+    ) -> anyhow::Result<()>;
+
+    fn decrypt_with_metadata_dyn(
+        &self,
+        src: &mut dyn Read,
+        dst: &mut dyn Write,
+    ) -> anyhow::Result<Option<Metadata>>;
+}
+
+impl<C: Cipher + Send + Sync> DynCipher for C {
+    fn encrypt_dyn(&self, mut src: &mut dyn Read, mut dst: &mut dyn Write) -> anyhow::Result<()> {
+        self.encrypt(&mut src, &mut dst)
+    }
+
+    fn decrypt_dyn(&self, mut src: &mut dyn Read, mut dst: &mut dyn Write) -> anyhow::Result<()> {
+        self.decrypt(&mut src, &mut dst)
+    }
+
+    fn supports_metadata_dyn(&self) -> bool {
+        self.supports_metadata()
+    }
+
+    fn encrypt_with_metadata_dyn(
+        &self,
+        metadata: &Metadata,
+        mut src: &mut dyn Read,
+        mut dst: &mut dyn Write,
+    ) -> anyhow::Result<()> {
+        self.encrypt_with_metadata(metadata, &mut src, &mut dst)
+    }
+
+    fn decrypt_with_metadata_dyn(
+        &self,
+        mut src: &mut dyn Read,
+        mut dst: &mut dyn Write,
+    ) -> anyhow::Result<Option<Metadata>> {
+        self.decrypt_with_metadata(&mut src, &mut dst)
+    }
+}
+
+// Builds a cipher from its key: a public or private key, a passphrase, or
+// whatever else the algorithm takes
+pub type CipherFactory = Box<dyn Fn(&str) -> anyhow::Result<Box<dyn DynCipher>> + Send + Sync>;
+
+// Cipher factories by the algorithm a format 5 header stores, so the algorithm
+// can come from a config file or an input file and applications can plug in
+// their own implementation of it.
+#[derive(Default)]
+pub struct CipherRegistry {
+    factories: BTreeMap<Algorithm, CipherFactory>,
+}
+
+impl CipherRegistry {
+    // An empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Ecies takes a hex public key to encrypt or private key to decrypt,
+    // Scrypt the passphrase
+    pub fn builtin() -> Self {
+        let mut registry = Self::new();
+        registry.register(Algorithm::Ecies, |key| Ok(Box::new(AesECCCipher::new(key))));
+        registry.register(Algorithm::Scrypt, |key| {
+            Ok(Box::new(PasswordCipher::new(key)))
+        });
+        registry
+    }
+
+    // Replaces any factory already registered for `algorithm`
+    pub fn register<F>(&mut self, algorithm: Algorithm, factory: F)
+    where
+        F: Fn(&str) -> anyhow::Result<Box<dyn DynCipher>> + Send + Sync + 'static,
+    {
+        self.factories.insert(algorithm, Box::new(factory));
+    }
+
+    pub fn create(&self, algorithm: Algorithm, key: &str) -> anyhow::Result<Box<dyn DynCipher>> {
+        let factory = self.factories.get(&algorithm).ok_or_else(|| {
+            anyhow::anyhow!("No cipher registered for algorithm {}", u8::from(algorithm))
+        })?;
+        factory(key)
+    }
+
+    // Registered algorithms in ID order
+    pub fn algorithms(&self) -> impl Iterator<Item = Algorithm> + '_ {
+        self.factories.keys().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xor::XorCipher;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    // the CLI shares one cipher between its worker threads
+    #[test]
+    fn ciphers_are_send_sync() {
+        assert_send_sync::<AesECCCipher>();
+        assert_send_sync::<PasswordCipher>();
+        assert_send_sync::<XorCipher>();
+    }
+
+    #[test]
+    fn registry_picks_cipher_at_runtime() {
+        let registry = CipherRegistry::builtin();
+        assert_eq!(
+            registry.algorithms().collect::<Vec<_>>(),
+            [Algorithm::Ecies, Algorithm::Scrypt]
+        );
+
+        let plaintext = b"chosen from the config";
+        let mut encrypted = Vec::new();
+        registry
+            .create(Algorithm::Scrypt, "passphrase")
+            .unwrap()
+            .encrypt_dyn(&mut &plaintext[..], &mut encrypted)
+            .unwrap();
+
+        // the algorithm of an input file comes from its header
+        let algorithm = match crate::format::read_header(&mut &encrypted[..]).unwrap() {
+            crate::format::Header::Extensible(header) => header.algorithm,
+            header => panic!("unexpected header {:?}", header),
+        };
+        let mut decrypted = Vec::new();
+        registry
+            .create(algorithm, "passphrase")
+            .unwrap()
+            .decrypt_dyn(&mut &encrypted[..], &mut decrypted)
+            .unwrap();
+        assert_eq!(decrypted, plaintext);
+
+        let mut registry = CipherRegistry::new();
+        assert!(registry.create(Algorithm::Ecies, "").is_err());
+        registry.register(Algorithm::Ecies, |_| Ok(Box::new(XorCipher::new(42))));
+        assert!(registry.create(Algorithm::Ecies, "").is_ok());
+    }
 }
@@ -0,0 +1,88 @@
+use std::fmt;
+
+use micro_uecc_safe::{KeyPair, PrivateKey, PublicKey};
+use openssl::nid::Nid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Curve {
+    #[default]
+    Secp256k1,
+    Secp256r1,
+}
+
+impl Curve {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Curve::Secp256k1 => "secp256k1",
+            Curve::Secp256r1 => "secp256r1",
+        }
+    }
+
+    pub fn uecc(&self) -> micro_uecc_safe::Curve {
+        match self {
+            Curve::Secp256k1 => micro_uecc_safe::Curve::Secp256k1,
+            Curve::Secp256r1 => micro_uecc_safe::Curve::Secp256r1,
+        }
+    }
+
+    // OpenSSL name of the curve, used for PEM/DER key files
+    pub(crate) fn nid(&self) -> Nid {
+        match self {
+            Curve::Secp256k1 => Nid::SECP256K1,
+            Curve::Secp256r1 => Nid::X9_62_PRIME256V1,
+        }
+    }
+
+    pub(crate) fn from_nid(nid: Nid) -> Option<Curve> {
+        [Curve::Secp256k1, Curve::Secp256r1]
+            .into_iter()
+            .find(|curve| curve.nid() == nid)
+    }
+
+    pub fn make_key(&self) -> anyhow::Result<KeyPair> {
+        KeyPair::generate(self.uecc())
+    }
+
+    pub fn public_key(&self, bytes: &[u8]) -> anyhow::Result<PublicKey> {
+        PublicKey::from_bytes(self.uecc(), bytes)
+    }
+
+    pub fn private_key(&self, bytes: &[u8]) -> anyhow::Result<PrivateKey> {
+        PrivateKey::from_bytes(self.uecc(), bytes)
+    }
+
+    pub(crate) fn shared_secret(
+        &self,
+        public_key: &[u8],
+        private_key: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        self.private_key(private_key)?
+            .shared_secret(&self.public_key(public_key)?)
+    }
+}
+
+impl fmt::Display for Curve {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl From<Curve> for u8 {
+    fn from(value: Curve) -> Self {
+        match value {
+            Curve::Secp256k1 => 0x01,
+            Curve::Secp256r1 => 0x02,
+        }
+    }
+}
+
+impl TryFrom<u8> for Curve {
+    type Error = &'static str;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x01 => Ok(Curve::Secp256k1),
+            0x02 => Ok(Curve::Secp256r1),
+            _ => Err("Unsupported curve"),
+        }
+    }
+}
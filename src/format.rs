@@ -0,0 +1,572 @@
+//! The file_cipher container format.
+//!
+//! Every container starts with the 14 byte magic `rs_file_cipher` followed by
+//! a big-endian u16 format number. Formats 1 to 4 have fixed size headers,
+//! format 5 has a length-prefixed header with extension fields:
+//!
+//! ```text
+//! magic       [u8; 14]  "rs_file_cipher"
+//! format      u16       5
+//! header_len  u32       length of the whole header, magic included
+//! algorithm   u8        1 = ECIES, 2 = scrypt, both with an AES-256-GCM STREAM payload
+//! chunk_size  u32       plaintext bytes per payload chunk
+//! salt        [u8; 32]  key derivation salt
+//! extensions            up to header_len, each one:
+//!   type      u16       high bit set = critical
+//!   length    u32
+//!   value     [u8; length]
+//! ```
+//!
+//! All integers are big-endian. A reader must reject a header with a critical
+//! extension it does not know, and may skip unknown non-critical ones, so new
+//! fields do not need a new format number.
+//!
+//! The payload follows the header as AES-256-GCM chunks of chunk_size
+//! plaintext bytes. The exact header bytes are the associated data of every
+//! chunk, so changing any header field, known to the reader or not, makes
+//! decryption fail.
+//!
+//! Format 5 is what the `aes` and `password` ciphers write. Readers from
+//! before it, up to release 1.1.0, reject it as an unsupported format.
+
+use byte_struct::*;
+
+use std::io::Read;
+
+use crate::curve::Curve;
+use crate::header;
+use crate::kdf;
+use crate::password::ScryptParams;
+use crate::version::Version;
+
+/// First bytes of every container.
+pub const MAGIC: &[u8] = header::MAGIC_BYTES;
+
+/// Largest format 5 header a reader accepts.
+pub const MAX_HEADER_LEN: u32 = 1 << 20;
+
+const CRITICAL: u16 = 0x8000;
+
+const EXTENSION_CURVE: u16 = CRITICAL | 0x0001;
+const EXTENSION_RECIPIENT: u16 = CRITICAL | 0x0002;
+const EXTENSION_SCRYPT: u16 = CRITICAL | 0x0003;
+const EXTENSION_SIGNED: u16 = CRITICAL | 0x0004;
+const EXTENSION_METADATA: u16 = CRITICAL | 0x0005;
+
+// type + length
+const EXTENSION_PREFIX_LEN: usize = 2 + 4;
+
+const KEY_INFO: &[u8] = b"file_cipher v5 aes-256-gcm key";
+const NONCE_INFO: &[u8] = b"file_cipher v5 aes-256-gcm nonce";
+
+/// How the payload key of a format 5 container is obtained.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Algorithm {
+    /// A random file key wrapped for every recipient public key.
+    Ecies,
+    /// A key derived from a passphrase with scrypt.
+    Scrypt,
+}
+
+impl From<Algorithm> for u8 {
+    fn from(value: Algorithm) -> Self {
+        match value {
+            Algorithm::Ecies => 0x01,
+            Algorithm::Scrypt => 0x02,
+        }
+    }
+}
+
+impl TryFrom<u8> for Algorithm {
+    type Error = &'static str;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x01 => Ok(Algorithm::Ecies),
+            0x02 => Ok(Algorithm::Scrypt),
+            _ => Err("Unsupported algorithm"),
+        }
+    }
+}
+
+/// The file key wrapped for one recipient.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recipient {
+    /// Ephemeral public key, uncompressed x || y.
+    pub ephemeral_key: [u8; header::ECC_PUBLIC_KEY_LEN],
+    /// The file key sealed with AES-256-GCM, tag included.
+    pub wrapped_key: [u8; header::WRAPPED_KEY_LEN],
+}
+
+impl Recipient {
+    pub(crate) fn from_stanza(stanza: &header::RecipientStanza) -> Self {
+        Recipient {
+            ephemeral_key: *stanza.key_bytes(),
+            wrapped_key: *stanza.wrapped_key_bytes(),
+        }
+    }
+}
+
+/// A format 5 header extension field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Extension {
+    /// Curve of the recipient keys, one u8.
+    Curve(Curve),
+    /// One per recipient, the ephemeral key then the wrapped file key.
+    Recipient(Recipient),
+    /// scrypt cost: log_n u8, r u32, p u32.
+    Scrypt(ScryptParams),
+    /// The payload is followed by a signature trailer. Empty value.
+    Signed,
+    /// The payload starts with a metadata record. Empty value.
+    Metadata,
+    /// A non-critical extension this version does not know.
+    Unknown { kind: u16, value: Vec<u8> },
+}
+
+impl Extension {
+    fn kind(&self) -> u16 {
+        match self {
+            Extension::Curve(_) => EXTENSION_CURVE,
+            Extension::Recipient(_) => EXTENSION_RECIPIENT,
+            Extension::Scrypt(_) => EXTENSION_SCRYPT,
+            Extension::Signed => EXTENSION_SIGNED,
+            Extension::Metadata => EXTENSION_METADATA,
+            Extension::Unknown { kind, .. } => *kind,
+        }
+    }
+
+    fn value(&self) -> Vec<u8> {
+        match self {
+            Extension::Curve(curve) => vec![(*curve).into()],
+            Extension::Recipient(recipient) => {
+                let mut value = recipient.ephemeral_key.to_vec();
+                value.extend_from_slice(&recipient.wrapped_key);
+                value
+            }
+            Extension::Scrypt(params) => {
+                let mut value = vec![params.log_n];
+                value.extend_from_slice(&params.r.to_be_bytes());
+                value.extend_from_slice(&params.p.to_be_bytes());
+                value
+            }
+            Extension::Signed | Extension::Metadata => Vec::new(),
+            Extension::Unknown { value, .. } => value.clone(),
+        }
+    }
+
+    fn parse(kind: u16, value: &[u8]) -> anyhow::Result<Self> {
+        let invalid = || anyhow::anyhow!("Invalid header extension 0x{:04x}", kind);
+        let extension = match kind {
+            EXTENSION_CURVE => match value {
+                [curve] => Extension::Curve(Curve::try_from(*curve).map_err(anyhow::Error::msg)?),
+                _ => return Err(invalid()),
+            },
+            EXTENSION_RECIPIENT => {
+                if value.len() != header::RecipientStanza::BYTE_LEN {
+                    return Err(invalid());
+                }
+                let stanza = header::RecipientStanza::read_bytes(value);
+                Extension::Recipient(Recipient::from_stanza(&stanza))
+            }
+            EXTENSION_SCRYPT => match value {
+                [log_n, r @ .., p0, p1, p2, p3] if r.len() == 4 => {
+                    Extension::Scrypt(ScryptParams {
+                        log_n: *log_n,
+                        r: u32::from_be_bytes([r[0], r[1], r[2], r[3]]),
+                        p: u32::from_be_bytes([*p0, *p1, *p2, *p3]),
+                    })
+                }
+                _ => return Err(invalid()),
+            },
+            EXTENSION_SIGNED | EXTENSION_METADATA if !value.is_empty() => return Err(invalid()),
+            EXTENSION_SIGNED => Extension::Signed,
+            EXTENSION_METADATA => Extension::Metadata,
+            kind if kind & CRITICAL != 0 => {
+                return Err(anyhow::anyhow!(
+                    "Unsupported critical header extension 0x{:04x}",
+                    kind
+                ))
+            }
+            kind => Extension::Unknown {
+                kind,
+                value: value.to_vec(),
+            },
+        };
+        Ok(extension)
+    }
+}
+
+/// A format 5 header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtensibleHeader {
+    pub algorithm: Algorithm,
+    pub chunk_size: u32,
+    pub salt: [u8; header::HKDF_SALT_LEN],
+    /// In file order.
+    pub extensions: Vec<Extension>,
+}
+
+impl ExtensibleHeader {
+    pub fn new(algorithm: Algorithm, chunk_size: u32, salt: [u8; header::HKDF_SALT_LEN]) -> Self {
+        ExtensibleHeader {
+            algorithm,
+            chunk_size,
+            salt,
+            extensions: Vec::new(),
+        }
+    }
+
+    pub fn curve(&self) -> Option<Curve> {
+        self.extensions
+            .iter()
+            .find_map(|extension| match extension {
+                Extension::Curve(curve) => Some(*curve),
+                _ => None,
+            })
+    }
+
+    pub fn recipients(&self) -> Vec<&Recipient> {
+        self.extensions
+            .iter()
+            .filter_map(|extension| match extension {
+                Extension::Recipient(recipient) => Some(recipient),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn scrypt(&self) -> Option<ScryptParams> {
+        self.extensions
+            .iter()
+            .find_map(|extension| match extension {
+                Extension::Scrypt(params) => Some(*params),
+                _ => None,
+            })
+    }
+
+    pub fn signed(&self) -> bool {
+        self.extensions.contains(&Extension::Signed)
+    }
+
+    pub fn metadata(&self) -> bool {
+        self.extensions.contains(&Extension::Metadata)
+    }
+
+    /// The exact bytes written to a file. An [`Extension::Unknown`] has to be
+    /// non-critical, which also keeps it from posing as a known extension.
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let mut extensions = Vec::new();
+        for extension in &self.extensions {
+            if let Extension::Unknown { kind, .. } = extension {
+                if kind & CRITICAL != 0 {
+                    return Err(anyhow::anyhow!(
+                        "Header extension 0x{:04x} is critical or known, it cannot be written as unknown",
+                        kind
+                    ));
+                }
+            }
+            let value = extension.value();
+            extensions.extend_from_slice(&extension.kind().to_be_bytes());
+            extensions.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            extensions.extend_from_slice(&value);
+        }
+        let header_len = header::ExtensibleHeaderPrefix::BYTE_LEN + extensions.len();
+        if header_len > MAX_HEADER_LEN as usize {
+            return Err(anyhow::anyhow!("The header is too large"));
+        }
+        let prefix = header::ExtensibleHeaderPrefix::new(
+            header_len as u32,
+            self.algorithm.into(),
+            self.chunk_size,
+            &self.salt,
+        );
+        let mut bytes = vec![0u8; header::ExtensibleHeaderPrefix::BYTE_LEN];
+        prefix.write_bytes(&mut bytes);
+        bytes.extend_from_slice(&extensions);
+        Ok(bytes)
+    }
+
+    // The payload key and nonce, from the file key or the scrypt output. The
+    // header needs no binding here, its bytes are the associated data.
+    pub(crate) fn payload_keys(
+        &self,
+        secret: &[u8],
+    ) -> anyhow::Result<([u8; 32], [u8; header::AES_GCM_NONCE_LEN])> {
+        let algorithm = [self.algorithm.into()];
+        let mut key = [0u8; 32];
+        let mut nonce = [0u8; header::AES_GCM_NONCE_LEN];
+        kdf::hkdf_sha256(secret, &self.salt, &[&algorithm, KEY_INFO], &mut key)?;
+        kdf::hkdf_sha256(secret, &self.salt, &[&algorithm, NONCE_INFO], &mut nonce)?;
+        Ok((key, nonce))
+    }
+
+    /// Parses a whole header, `bytes` must be exactly header_len long.
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let prefix_len = header::ExtensibleHeaderPrefix::BYTE_LEN;
+        if bytes.len() < prefix_len {
+            return Err(anyhow::anyhow!("The header is truncated"));
+        }
+        let prefix = header::ExtensibleHeaderPrefix::try_from(&bytes[..prefix_len])?;
+        if prefix.header_len() as usize != bytes.len() {
+            return Err(anyhow::anyhow!("The header length does not match"));
+        }
+        let algorithm = Algorithm::try_from(prefix.algorithm()).map_err(anyhow::Error::msg)?;
+
+        let mut extensions = Vec::new();
+        let mut rest = &bytes[prefix_len..];
+        while !rest.is_empty() {
+            if rest.len() < EXTENSION_PREFIX_LEN {
+                return Err(anyhow::anyhow!("The header extensions are truncated"));
+            }
+            let kind = u16::from_be_bytes([rest[0], rest[1]]);
+            let len = u32::from_be_bytes([rest[2], rest[3], rest[4], rest[5]]) as usize;
+            let value = rest
+                .get(EXTENSION_PREFIX_LEN..EXTENSION_PREFIX_LEN + len)
+                .ok_or_else(|| anyhow::anyhow!("The header extensions are truncated"))?;
+            extensions.push(Extension::parse(kind, value)?);
+            rest = &rest[EXTENSION_PREFIX_LEN + len..];
+        }
+
+        let header = ExtensibleHeader {
+            algorithm,
+            chunk_size: prefix.chunk_size(),
+            salt: *prefix.salt_bytes(),
+            extensions,
+        };
+        header.check()?;
+        Ok(header)
+    }
+
+    // Every extension a reader relies on appears once, recipients aside
+    fn check(&self) -> anyhow::Result<()> {
+        for kind in [
+            EXTENSION_CURVE,
+            EXTENSION_SCRYPT,
+            EXTENSION_SIGNED,
+            EXTENSION_METADATA,
+        ] {
+            let count = self
+                .extensions
+                .iter()
+                .filter(|extension| extension.kind() == kind)
+                .count();
+            if count > 1 {
+                return Err(anyhow::anyhow!("Duplicate header extension 0x{:04x}", kind));
+            }
+        }
+        match self.algorithm {
+            Algorithm::Ecies => {
+                let recipients = self.recipients().len();
+                if self.curve().is_none() {
+                    return Err(anyhow::anyhow!("The header has no curve"));
+                }
+                if recipients == 0 || recipients > header::MAX_RECIPIENTS as usize {
+                    return Err(anyhow::anyhow!(
+                        "Unsupported number of recipients: {}",
+                        recipients
+                    ));
+                }
+            }
+            Algorithm::Scrypt => {
+                if self.scrypt().is_none() {
+                    return Err(anyhow::anyhow!("The header has no scrypt parameters"));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A parsed container header, one variant per format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Header {
+    /// Format 1, every byte xor'ed with a secret value.
+    Xor,
+    /// Format 2, ECIES with AES-256-ECB. Read only.
+    LegacyAesEcb {
+        ephemeral_key: [u8; header::ECC_PUBLIC_KEY_LEN],
+        iv: [u8; 16],
+    },
+    /// Format 3, ECIES with an AES-256-GCM STREAM payload.
+    AesGcm {
+        curve: Curve,
+        chunk_size: u32,
+        salt: [u8; header::HKDF_SALT_LEN],
+        signed: bool,
+        metadata: bool,
+        recipients: Vec<Recipient>,
+    },
+    /// Format 4, scrypt with an AES-256-GCM STREAM payload.
+    Password {
+        scrypt: ScryptParams,
+        chunk_size: u32,
+        salt: [u8; header::HKDF_SALT_LEN],
+    },
+    /// Format 5.
+    Extensible(ExtensibleHeader),
+}
+
+impl Header {
+    pub fn version(&self) -> Version {
+        match self {
+            Header::Xor => Version::V1,
+            Header::LegacyAesEcb { .. } => Version::V2,
+            Header::AesGcm { .. } => Version::V3,
+            Header::Password { .. } => Version::V4,
+            Header::Extensible(_) => Version::V5,
+        }
+    }
+}
+
+/// Reads the header of any container, leaving `src` at the first payload byte.
+pub fn read_header<R: Read>(src: &mut R) -> anyhow::Result<Header> {
+    let mut prefix = [0u8; header::FORMAT_PREFIX_LEN];
+    src.read_exact(&mut prefix)?;
+    let version = header::read_format(&prefix)?;
+    let fixed_len = match version {
+        Version::V1 => header::XorHeader::BYTE_LEN,
+        Version::V2 => header::AesECCHeader::BYTE_LEN,
+        Version::V3 => header::AesECCGcmHeader::BYTE_LEN,
+        Version::V4 => header::PasswordHeader::BYTE_LEN,
+        Version::V5 => header::ExtensibleHeaderPrefix::BYTE_LEN,
+    };
+    let mut bytes = prefix.to_vec();
+    bytes.resize(fixed_len, 0);
+    src.read_exact(&mut bytes[header::FORMAT_PREFIX_LEN..])?;
+
+    let header = match version {
+        Version::V1 => {
+            header::XorHeader::try_from(&bytes[..])?;
+            Header::Xor
+        }
+        Version::V2 => {
+            let header = header::AesECCHeader::try_from(&bytes[..])?;
+            Header::LegacyAesEcb {
+                ephemeral_key: *header.key_bytes(),
+                iv: *header.iv_bytes(),
+            }
+        }
+        Version::V3 => {
+            let header = header::AesECCGcmHeader::try_from(&bytes[..])?;
+            let mut recipients = vec![0u8; header.recipients_len()];
+            src.read_exact(&mut recipients)?;
+            Header::AesGcm {
+                curve: header.curve(),
+                chunk_size: header.chunk_size(),
+                salt: *header.salt_bytes(),
+                signed: header.flags() & header::FLAG_SIGNED != 0,
+                metadata: header.flags() & header::FLAG_METADATA != 0,
+                recipients: recipients
+                    .chunks_exact(header::RecipientStanza::BYTE_LEN)
+                    .map(|bytes| {
+                        Recipient::from_stanza(&header::RecipientStanza::read_bytes(bytes))
+                    })
+                    .collect(),
+            }
+        }
+        Version::V4 => {
+            let header = header::PasswordHeader::try_from(&bytes[..])?;
+            Header::Password {
+                scrypt: ScryptParams {
+                    log_n: header.log_n(),
+                    r: header.r(),
+                    p: header.p(),
+                },
+                chunk_size: header.chunk_size(),
+                salt: *header.salt_bytes(),
+            }
+        }
+        Version::V5 => Header::Extensible(read_extensible_rest(bytes, src)?.0),
+    };
+    Ok(header)
+}
+
+// The rest of a format 5 header after its first `prefix` bytes, along with
+// the exact header bytes the payload is authenticated against.
+pub(crate) fn read_extensible_header<R: Read>(
+    prefix: &[u8],
+    src: &mut R,
+) -> anyhow::Result<(ExtensibleHeader, Vec<u8>)> {
+    let mut bytes = prefix.to_vec();
+    bytes.resize(header::ExtensibleHeaderPrefix::BYTE_LEN, 0);
+    src.read_exact(&mut bytes[prefix.len()..])?;
+    read_extensible_rest(bytes, src)
+}
+
+// Length of a whole format 5 header from its fixed size prefix
+pub(crate) fn extensible_header_len(prefix: &[u8]) -> anyhow::Result<usize> {
+    let prefix = header::ExtensibleHeaderPrefix::try_from(prefix)?;
+    if prefix.header_len() > MAX_HEADER_LEN {
+        return Err(anyhow::anyhow!(
+            "The header is too large: {} bytes",
+            prefix.header_len()
+        ));
+    }
+    Ok(prefix.header_len() as usize)
+}
+
+fn read_extensible_rest<R: Read>(
+    mut bytes: Vec<u8>,
+    src: &mut R,
+) -> anyhow::Result<(ExtensibleHeader, Vec<u8>)> {
+    let fixed_len = header::ExtensibleHeaderPrefix::BYTE_LEN;
+    bytes.resize(extensible_header_len(&bytes[..fixed_len])?, 0);
+    src.read_exact(&mut bytes[fixed_len..])?;
+    let header = ExtensibleHeader::from_bytes(&bytes)?;
+    Ok((header, bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ecies_header() -> ExtensibleHeader {
+        let mut header = ExtensibleHeader::new(Algorithm::Ecies, 65536, [7u8; 32]);
+        header.extensions.push(Extension::Curve(Curve::Secp256r1));
+        header.extensions.push(Extension::Recipient(Recipient {
+            ephemeral_key: [1u8; 64],
+            wrapped_key: [2u8; 48],
+        }));
+        header.extensions.push(Extension::Signed);
+        header
+    }
+
+    #[test]
+    fn extensible_header_roundtrip() {
+        let mut header = ecies_header();
+        header.extensions.push(Extension::Unknown {
+            kind: 0x0042,
+            value: b"skipped by older readers".to_vec(),
+        });
+        let mut bytes = header.to_bytes().unwrap();
+        bytes.extend_from_slice(b"payload");
+
+        let mut src = &bytes[..];
+        let parsed = read_header(&mut src).unwrap();
+        assert_eq!(parsed, Header::Extensible(header));
+        assert_eq!(src, b"payload");
+    }
+
+    #[test]
+    fn unknown_critical_extension_is_rejected() {
+        let header = ecies_header();
+        for kind in [CRITICAL | 0x0042, EXTENSION_SIGNED] {
+            let mut header = header.clone();
+            header.extensions.push(Extension::Unknown {
+                kind,
+                value: Vec::new(),
+            });
+            assert!(header.to_bytes().is_err());
+        }
+
+        // written by hand, as a newer version would
+        let mut bytes = header.to_bytes().unwrap();
+        bytes.extend_from_slice(&(CRITICAL | 0x0042).to_be_bytes());
+        bytes.extend_from_slice(&0u32.to_be_bytes());
+        let header_len = bytes.len() as u32;
+        let offset = header::FORMAT_PREFIX_LEN;
+        bytes[offset..offset + 4].copy_from_slice(&header_len.to_be_bytes());
+        let err = read_header(&mut &bytes[..]).unwrap_err();
+        assert!(err.to_string().contains("Unsupported critical"));
+    }
+}
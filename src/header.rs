@@ -1,7 +1,7 @@
 use byte_struct::*;
 
+use crate::curve::Curve;
 use crate::error::FileCipherError;
-use crate::utils;
 use crate::version::Version;
 
 pub(crate) const MAGIC_BYTES: &[u8] = b"rs_file_cipher";
@@ -10,6 +10,45 @@ pub(crate) const MAGIC_BYTES_LEN: usize = MAGIC_BYTES.len();
 pub(crate) const ECC_PUBLIC_KEY_LEN: usize = 64;
 pub(crate) const ECC_PRIVATE_KEY_LEN: usize = 32;
 
+pub(crate) const AES_GCM_NONCE_LEN: usize = 12;
+pub(crate) const AES_GCM_TAG_LEN: usize = 16;
+pub(crate) const HKDF_SALT_LEN: usize = 32;
+
+// random per-file key, wrapped with AES-256-GCM for every recipient
+pub(crate) const FILE_KEY_LEN: usize = 32;
+pub(crate) const WRAPPED_KEY_LEN: usize = FILE_KEY_LEN + AES_GCM_TAG_LEN;
+pub(crate) const MAX_RECIPIENTS: u16 = 1024;
+
+pub(crate) const ECC_SIGNATURE_LEN: usize = 64;
+// signer curve + signer public key + signature, appended after the payload
+pub(crate) const SIGNATURE_TRAILER_LEN: usize = 1 + ECC_PUBLIC_KEY_LEN + ECC_SIGNATURE_LEN;
+
+pub(crate) const FLAG_SIGNED: u8 = 0x01;
+// the payload starts with a metadata record
+pub(crate) const FLAG_METADATA: u8 = 0x02;
+const KNOWN_FLAGS: u8 = FLAG_SIGNED | FLAG_METADATA;
+
+// magic + format
+pub(crate) const FORMAT_PREFIX_LEN: usize = MAGIC_BYTES_LEN + 2;
+
+fn check_magic(bytes: &[u8]) -> Result<(), FileCipherError> {
+    if bytes.len() < MAGIC_BYTES_LEN || &bytes[0..MAGIC_BYTES_LEN] != MAGIC_BYTES {
+        return Err(FileCipherError::Other(
+            "The input file is not a file encrypted by file_cipher".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn read_format(bytes: &[u8]) -> Result<Version, FileCipherError> {
+    if bytes.len() < FORMAT_PREFIX_LEN {
+        return Err(FileCipherError::NotLongEnough(FORMAT_PREFIX_LEN));
+    }
+    check_magic(bytes)?;
+    let format = u16::from_be_bytes([bytes[MAGIC_BYTES_LEN], bytes[MAGIC_BYTES_LEN + 1]]);
+    Version::try_from(format).map_err(|msg| FileCipherError::Other(msg.to_string()))
+}
+
 #[derive(ByteStruct, PartialEq, Debug)]
 #[byte_struct_be]
 pub(crate) struct XorHeader {
@@ -39,16 +78,7 @@ impl TryFrom<&[u8]> for XorHeader {
             return Err(FileCipherError::NotLongEnough(XorHeader::BYTE_LEN));
         }
 
-        let index = MAGIC_BYTES
-            .iter()
-            .zip(bytes[0..MAGIC_BYTES_LEN].iter())
-            .position(|(a, b)| a != b);
-
-        if let Some(_) = index {
-            return Err(FileCipherError::Other(
-                "The input file is not a file encrypted by file_cipher".to_owned(),
-            ));
-        }
+        check_magic(bytes)?;
 
         let header = XorHeader::read_bytes(bytes);
         if let Err(msg) = Version::try_from(header.format) {
@@ -68,7 +98,8 @@ pub(crate) struct AesECCHeader {
 }
 
 impl AesECCHeader {
-    pub(crate) fn new(publickey: &str, iv: &[u8; 16]) -> Self {
+    #[cfg(test)]
+    pub(crate) fn new(publickey: &[u8], iv: &[u8; 16]) -> Self {
         let mut h = AesECCHeader {
             magic: [0u8; MAGIC_BYTES_LEN],
             format: Version::V2.into(),
@@ -76,16 +107,11 @@ impl AesECCHeader {
             iv: [0u8; 16],
         };
         h.magic.copy_from_slice(MAGIC_BYTES);
-        let key_bytes = utils::decode_hex(publickey).unwrap();
-        h.key.copy_from_slice(&key_bytes);
+        h.key.copy_from_slice(publickey);
         h.iv.copy_from_slice(iv);
         h
     }
 
-    pub(crate) fn format(&self) -> u16 {
-        self.format
-    }
-
     pub(crate) fn key_bytes(&self) -> &[u8; ECC_PUBLIC_KEY_LEN] {
         &self.key
     }
@@ -102,21 +128,320 @@ impl TryFrom<&[u8]> for AesECCHeader {
             return Err(FileCipherError::NotLongEnough(AesECCHeader::BYTE_LEN));
         }
 
-        let index = MAGIC_BYTES
-            .iter()
-            .zip(bytes[0..MAGIC_BYTES_LEN].iter())
-            .position(|(a, b)| a != b);
+        check_magic(bytes)?;
 
-        if let Some(_) = index {
-            return Err(FileCipherError::Other(
-                "The input file is not a file encrypted by file_cipher".to_owned(),
-            ));
+        let header = AesECCHeader::read_bytes(bytes);
+        if let Err(msg) = Version::try_from(header.format) {
+            return Err(FileCipherError::Other(msg.to_string()));
         }
 
-        let header = AesECCHeader::read_bytes(bytes);
+        Ok(header)
+    }
+}
+
+// Fixed part of the V3 header, followed by `recipients` RecipientStanza entries
+#[derive(ByteStruct, PartialEq, Debug)]
+#[byte_struct_be]
+pub(crate) struct AesECCGcmHeader {
+    magic: [u8; MAGIC_BYTES_LEN],
+    format: u16,
+    curve: u8,
+    flags: u8,
+    chunk_size: u32,
+    salt: [u8; HKDF_SALT_LEN],
+    recipients: u16,
+}
+
+impl AesECCGcmHeader {
+    #[cfg(test)]
+    pub(crate) fn new(
+        curve: Curve,
+        flags: u8,
+        chunk_size: u32,
+        salt: &[u8; HKDF_SALT_LEN],
+        recipients: u16,
+    ) -> Self {
+        let mut h = AesECCGcmHeader {
+            magic: [0u8; MAGIC_BYTES_LEN],
+            format: Version::V3.into(),
+            curve: curve.into(),
+            flags,
+            chunk_size,
+            salt: [0u8; HKDF_SALT_LEN],
+            recipients,
+        };
+        h.magic.copy_from_slice(MAGIC_BYTES);
+        h.salt.copy_from_slice(salt);
+        h
+    }
+
+    pub(crate) fn curve(&self) -> Curve {
+        // validated in try_from
+        Curve::try_from(self.curve).unwrap_or_default()
+    }
+
+    pub(crate) fn flags(&self) -> u8 {
+        self.flags
+    }
+
+    pub(crate) fn chunk_size(&self) -> u32 {
+        self.chunk_size
+    }
+
+    pub(crate) fn salt_bytes(&self) -> &[u8; HKDF_SALT_LEN] {
+        &self.salt
+    }
+
+    pub(crate) fn recipients(&self) -> u16 {
+        self.recipients
+    }
+
+    // length of the recipient table that follows the fixed header
+    pub(crate) fn recipients_len(&self) -> usize {
+        self.recipients as usize * RecipientStanza::BYTE_LEN
+    }
+
+    // every field but the salt, binds the derived keys to this header
+    pub(crate) fn kdf_info(&self) -> Vec<u8> {
+        let mut info = Vec::with_capacity(FORMAT_PREFIX_LEN + 2 + 4 + 2);
+        info.extend_from_slice(&self.magic);
+        info.extend_from_slice(&self.format.to_be_bytes());
+        info.push(self.curve);
+        info.push(self.flags);
+        info.extend_from_slice(&self.chunk_size.to_be_bytes());
+        info.extend_from_slice(&self.recipients.to_be_bytes());
+        info
+    }
+}
+
+impl TryFrom<&[u8]> for AesECCGcmHeader {
+    type Error = FileCipherError;
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        if bytes.len() != AesECCGcmHeader::BYTE_LEN {
+            return Err(FileCipherError::NotLongEnough(AesECCGcmHeader::BYTE_LEN));
+        }
+
+        check_magic(bytes)?;
+
+        let header = AesECCGcmHeader::read_bytes(bytes);
         if let Err(msg) = Version::try_from(header.format) {
             return Err(FileCipherError::Other(msg.to_string()));
         }
+        if let Err(msg) = Curve::try_from(header.curve) {
+            return Err(FileCipherError::Other(msg.to_string()));
+        }
+        if header.flags & !KNOWN_FLAGS != 0 {
+            return Err(FileCipherError::Other(
+                "Unsupported header flags".to_owned(),
+            ));
+        }
+        if header.recipients == 0 || header.recipients > MAX_RECIPIENTS {
+            return Err(FileCipherError::Other(format!(
+                "Unsupported number of recipients: {}",
+                header.recipients
+            )));
+        }
+
+        Ok(header)
+    }
+}
+
+// The file key wrapped for one recipient, under a key agreed between a fresh
+// ephemeral key pair and the recipient's public key.
+#[derive(ByteStruct, PartialEq, Debug)]
+#[byte_struct_be]
+pub(crate) struct RecipientStanza {
+    key: [u8; ECC_PUBLIC_KEY_LEN],
+    wrapped_key: [u8; WRAPPED_KEY_LEN],
+}
+
+impl RecipientStanza {
+    #[cfg(test)]
+    pub(crate) fn new(publickey: &[u8], wrapped_key: &[u8]) -> Self {
+        let mut h = RecipientStanza {
+            key: [0u8; ECC_PUBLIC_KEY_LEN],
+            wrapped_key: [0u8; WRAPPED_KEY_LEN],
+        };
+        h.key.copy_from_slice(publickey);
+        h.wrapped_key.copy_from_slice(wrapped_key);
+        h
+    }
+
+    pub(crate) fn key_bytes(&self) -> &[u8; ECC_PUBLIC_KEY_LEN] {
+        &self.key
+    }
+
+    pub(crate) fn wrapped_key_bytes(&self) -> &[u8; WRAPPED_KEY_LEN] {
+        &self.wrapped_key
+    }
+}
+
+#[derive(ByteStruct, PartialEq, Debug)]
+#[byte_struct_be]
+pub(crate) struct PasswordHeader {
+    magic: [u8; MAGIC_BYTES_LEN],
+    format: u16,
+    log_n: u8,
+    r: u32,
+    p: u32,
+    chunk_size: u32,
+    salt: [u8; HKDF_SALT_LEN],
+}
+
+impl PasswordHeader {
+    #[cfg(test)]
+    pub(crate) fn new(
+        log_n: u8,
+        r: u32,
+        p: u32,
+        chunk_size: u32,
+        salt: &[u8; HKDF_SALT_LEN],
+    ) -> Self {
+        let mut h = PasswordHeader {
+            magic: [0u8; MAGIC_BYTES_LEN],
+            format: Version::V4.into(),
+            log_n,
+            r,
+            p,
+            chunk_size,
+            salt: [0u8; HKDF_SALT_LEN],
+        };
+        h.magic.copy_from_slice(MAGIC_BYTES);
+        h.salt.copy_from_slice(salt);
+        h
+    }
+
+    pub(crate) fn log_n(&self) -> u8 {
+        self.log_n
+    }
+
+    pub(crate) fn r(&self) -> u32 {
+        self.r
+    }
+
+    pub(crate) fn p(&self) -> u32 {
+        self.p
+    }
+
+    pub(crate) fn chunk_size(&self) -> u32 {
+        self.chunk_size
+    }
+
+    pub(crate) fn salt_bytes(&self) -> &[u8; HKDF_SALT_LEN] {
+        &self.salt
+    }
+
+    // every field but the salt, binds the derived keys to this header
+    pub(crate) fn kdf_info(&self) -> Vec<u8> {
+        let mut info = Vec::with_capacity(FORMAT_PREFIX_LEN + 1 + 4 * 3);
+        info.extend_from_slice(&self.magic);
+        info.extend_from_slice(&self.format.to_be_bytes());
+        info.push(self.log_n);
+        info.extend_from_slice(&self.r.to_be_bytes());
+        info.extend_from_slice(&self.p.to_be_bytes());
+        info.extend_from_slice(&self.chunk_size.to_be_bytes());
+        info
+    }
+}
+
+impl TryFrom<&[u8]> for PasswordHeader {
+    type Error = FileCipherError;
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        if bytes.len() != PasswordHeader::BYTE_LEN {
+            return Err(FileCipherError::NotLongEnough(PasswordHeader::BYTE_LEN));
+        }
+
+        check_magic(bytes)?;
+
+        let header = PasswordHeader::read_bytes(bytes);
+        match Version::try_from(header.format) {
+            Ok(Version::V4) => {}
+            Ok(_) => {
+                return Err(FileCipherError::Other(
+                    "The input file was not encrypted with a passphrase".to_owned(),
+                ))
+            }
+            Err(msg) => return Err(FileCipherError::Other(msg.to_string())),
+        }
+
+        Ok(header)
+    }
+}
+
+// Fixed part of the V5 header, followed by TLV extensions up to header_len.
+// The layout is documented in the format module.
+#[derive(ByteStruct, PartialEq, Debug)]
+#[byte_struct_be]
+pub(crate) struct ExtensibleHeaderPrefix {
+    magic: [u8; MAGIC_BYTES_LEN],
+    format: u16,
+    header_len: u32,
+    algorithm: u8,
+    chunk_size: u32,
+    salt: [u8; HKDF_SALT_LEN],
+}
+
+impl ExtensibleHeaderPrefix {
+    pub(crate) fn new(
+        header_len: u32,
+        algorithm: u8,
+        chunk_size: u32,
+        salt: &[u8; HKDF_SALT_LEN],
+    ) -> Self {
+        let mut h = ExtensibleHeaderPrefix {
+            magic: [0u8; MAGIC_BYTES_LEN],
+            format: Version::V5.into(),
+            header_len,
+            algorithm,
+            chunk_size,
+            salt: [0u8; HKDF_SALT_LEN],
+        };
+        h.magic.copy_from_slice(MAGIC_BYTES);
+        h.salt.copy_from_slice(salt);
+        h
+    }
+
+    pub(crate) fn header_len(&self) -> u32 {
+        self.header_len
+    }
+
+    pub(crate) fn algorithm(&self) -> u8 {
+        self.algorithm
+    }
+
+    pub(crate) fn chunk_size(&self) -> u32 {
+        self.chunk_size
+    }
+
+    pub(crate) fn salt_bytes(&self) -> &[u8; HKDF_SALT_LEN] {
+        &self.salt
+    }
+}
+
+impl TryFrom<&[u8]> for ExtensibleHeaderPrefix {
+    type Error = FileCipherError;
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        if bytes.len() != ExtensibleHeaderPrefix::BYTE_LEN {
+            return Err(FileCipherError::NotLongEnough(
+                ExtensibleHeaderPrefix::BYTE_LEN,
+            ));
+        }
+
+        check_magic(bytes)?;
+
+        let header = ExtensibleHeaderPrefix::read_bytes(bytes);
+        if header.format != u16::from(Version::V5) {
+            return Err(FileCipherError::Other(format!(
+                "Expected format 5, found {}",
+                header.format
+            )));
+        }
+        if (header.header_len as usize) < ExtensibleHeaderPrefix::BYTE_LEN {
+            return Err(FileCipherError::Other(format!(
+                "Invalid header length: {}",
+                header.header_len
+            )));
+        }
 
         Ok(header)
     }
@@ -0,0 +1,168 @@
+use std::io::Read;
+
+use crate::curve::Curve;
+use crate::format::{self, Algorithm, Header};
+use crate::header;
+use crate::password::ScryptParams;
+use crate::utils;
+use crate::version::Version;
+
+// What the header of a container says, read without any key. Fields that do
+// not exist in a format are None.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContainerInfo {
+    pub format: u16,
+    pub algorithm: &'static str,
+    pub curve: Option<Curve>,
+    // hex, one per recipient
+    pub ephemeral_keys: Vec<String>,
+    // hex
+    pub iv: Option<String>,
+    // hex, the key derivation salt
+    pub salt: Option<String>,
+    pub chunk_size: Option<u32>,
+    pub recipients: Option<u16>,
+    // None for formats that cannot be signed or carry metadata
+    pub signed: Option<bool>,
+    pub metadata: Option<bool>,
+    pub scrypt: Option<ScryptParams>,
+}
+
+impl ContainerInfo {
+    fn new(version: Version, algorithm: &'static str) -> Self {
+        ContainerInfo {
+            format: version.into(),
+            algorithm,
+            curve: None,
+            ephemeral_keys: Vec::new(),
+            iv: None,
+            salt: None,
+            chunk_size: None,
+            recipients: None,
+            signed: None,
+            metadata: None,
+            scrypt: None,
+        }
+    }
+}
+
+// Number of leading bytes `detect` needs: magic, format, and for format 5
+// the header length and the algorithm
+pub const PREFIX_LEN: usize = header::FORMAT_PREFIX_LEN + 4 + 1;
+
+// The format of a container from its first PREFIX_LEN bytes, along with the
+// algorithm for format 5. A format 1 file can be shorter than PREFIX_LEN.
+pub fn detect(prefix: &[u8]) -> anyhow::Result<(Version, Option<Algorithm>)> {
+    let version = header::read_format(prefix)?;
+    if version != Version::V5 {
+        return Ok((version, None));
+    }
+    let algorithm = prefix
+        .get(PREFIX_LEN - 1)
+        .ok_or_else(|| anyhow::anyhow!("The header is truncated"))?;
+    let algorithm = Algorithm::try_from(*algorithm).map_err(anyhow::Error::msg)?;
+    Ok((version, Some(algorithm)))
+}
+
+// Ok(None) when `src` is not a file_cipher container at all. A container
+// with a damaged or truncated header is an error.
+pub fn inspect<R: Read>(src: &mut R) -> anyhow::Result<Option<ContainerInfo>> {
+    let mut prefix = Vec::with_capacity(header::FORMAT_PREFIX_LEN);
+    src.take(header::FORMAT_PREFIX_LEN as u64)
+        .read_to_end(&mut prefix)?;
+    if prefix.len() < header::FORMAT_PREFIX_LEN || !prefix.starts_with(header::MAGIC_BYTES) {
+        return Ok(None);
+    }
+    let header = format::read_header(&mut (&prefix[..]).chain(src))?;
+    let version = header.version();
+
+    let info = match header {
+        Header::Xor => ContainerInfo::new(version, "xor"),
+        Header::LegacyAesEcb { ephemeral_key, iv } => ContainerInfo {
+            curve: Some(Curve::Secp256k1),
+            ephemeral_keys: vec![utils::encode_hex(&ephemeral_key)],
+            iv: Some(utils::encode_hex(&iv)),
+            recipients: Some(1),
+            ..ContainerInfo::new(version, "ecies aes-256-ecb")
+        },
+        Header::AesGcm {
+            curve,
+            chunk_size,
+            salt,
+            signed,
+            metadata,
+            recipients,
+        } => ContainerInfo {
+            curve: Some(curve),
+            ephemeral_keys: recipients
+                .iter()
+                .map(|recipient| utils::encode_hex(&recipient.ephemeral_key))
+                .collect(),
+            salt: Some(utils::encode_hex(&salt)),
+            chunk_size: Some(chunk_size),
+            recipients: Some(recipients.len() as u16),
+            signed: Some(signed),
+            metadata: Some(metadata),
+            ..ContainerInfo::new(version, "ecies aes-256-gcm stream")
+        },
+        Header::Password {
+            scrypt,
+            chunk_size,
+            salt,
+        } => ContainerInfo {
+            salt: Some(utils::encode_hex(&salt)),
+            chunk_size: Some(chunk_size),
+            scrypt: Some(scrypt),
+            ..ContainerInfo::new(version, "scrypt aes-256-gcm stream")
+        },
+        Header::Extensible(header) => {
+            let algorithm = match header.algorithm {
+                Algorithm::Ecies => "ecies aes-256-gcm stream",
+                Algorithm::Scrypt => "scrypt aes-256-gcm stream",
+            };
+            let recipients = header.recipients();
+            ContainerInfo {
+                curve: header.curve(),
+                ephemeral_keys: recipients
+                    .iter()
+                    .map(|recipient| utils::encode_hex(&recipient.ephemeral_key))
+                    .collect(),
+                salt: Some(utils::encode_hex(&header.salt)),
+                chunk_size: Some(header.chunk_size),
+                recipients: (!recipients.is_empty()).then_some(recipients.len() as u16),
+                signed: Some(header.signed()),
+                metadata: Some(header.metadata()),
+                scrypt: header.scrypt(),
+                ..ContainerInfo::new(version, algorithm)
+            }
+        }
+    };
+    Ok(Some(info))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aes_ecc::AesECCCipher;
+    use crate::cipher::Cipher;
+
+    #[test]
+    fn inspect_v5() {
+        let first = Curve::Secp256r1.make_key().unwrap();
+        let second = Curve::Secp256r1.make_key().unwrap();
+        let mut encrypted = Vec::new();
+        AesECCCipher::with_curve(&first.public_key.to_hex(), Curve::Secp256r1)
+            .add_recipient(&second.public_key.to_hex())
+            .encrypt(&mut &b"content"[..], &mut encrypted)
+            .unwrap();
+
+        let info = inspect(&mut &encrypted[..]).unwrap().unwrap();
+        assert_eq!(info.format, 5);
+        assert_eq!(info.curve, Some(Curve::Secp256r1));
+        assert_eq!(info.recipients, Some(2));
+        assert_eq!(info.ephemeral_keys.len(), 2);
+        assert_eq!(info.signed, Some(false));
+
+        assert_eq!(inspect(&mut &b"plain text"[..]).unwrap(), None);
+    }
+}
@@ -0,0 +1,219 @@
+use std::io::{self, Read, Write};
+
+use crate::aes_ecc::{AesECCCipher, Decryptor, Encryptor};
+use crate::metadata::Metadata;
+use crate::stream::{Transform, BUFFER_SIZE};
+
+// Encrypts everything written to it into a format 5 container on `inner`.
+// The last chunk can only be written once the input is complete, so the
+// container is truncated unless finish() is called. Functions that take a
+// writer by value, like serde_json::to_writer, should get `&mut writer` so it
+// can be finished afterwards.
+pub struct EncryptingWriter<W: Write> {
+    inner: Option<W>,
+    encryptor: Option<Encryptor>,
+    // ciphertext not written to `inner` yet, starting with the header
+    pending: Vec<u8>,
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    pub fn new(inner: W, public_key: &str) -> anyhow::Result<Self> {
+        Self::with_encryptor(inner, AesECCCipher::new(public_key).encryptor()?)
+    }
+
+    // For more recipients, signing or a metadata record
+    pub fn with_encryptor(inner: W, mut encryptor: Encryptor) -> anyhow::Result<Self> {
+        Ok(EncryptingWriter {
+            inner: Some(inner),
+            pending: encryptor.header()?,
+            encryptor: Some(encryptor),
+        })
+    }
+
+    // Writes the last chunk and the signature trailer, and hands back the
+    // inner writer
+    pub fn finish(mut self) -> anyhow::Result<W> {
+        if let (Some(encryptor), Some(inner)) = (self.encryptor.take(), self.inner.as_mut()) {
+            encryptor.finalize_into(&mut self.pending)?;
+            inner.write_all(&self.pending)?;
+            inner.flush()?;
+        }
+        self.inner
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("The writer was already finished"))
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let (Some(encryptor), Some(inner)) = (self.encryptor.as_mut(), self.inner.as_mut()) else {
+            return Err(io::Error::other("The writer was already finished"));
+        };
+        encryptor
+            .update_into(data, &mut self.pending)
+            .map_err(io::Error::other)?;
+        if !self.pending.is_empty() {
+            inner.write_all(&self.pending)?;
+            self.pending.clear();
+        }
+        Ok(data.len())
+    }
+
+    // Only flushes `inner`, a partial chunk stays buffered until it is full
+    // or finish() is called
+    fn flush(&mut self) -> io::Result<()> {
+        match self.inner.as_mut() {
+            Some(inner) => inner.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Drop for EncryptingWriter<W> {
+    fn drop(&mut self) {
+        if self.encryptor.is_some() {
+            log::warn!("EncryptingWriter dropped without finish(), the container is truncated");
+        }
+    }
+}
+
+// Reads the plaintext of a format 5 container from `inner`. Every chunk is
+// authenticated as part of the container before it is returned, the last
+// one when `inner` reaches its end. The signature, and so the signer required
+// by a verifying Decryptor, is only checked at that end too: until finish()
+// returns Ok, the plaintext read may come from an unknown sender.
+pub struct DecryptingReader<R: Read> {
+    inner: Option<R>,
+    decryptor: Option<Decryptor>,
+    buffer: Vec<u8>,
+    plaintext: Vec<u8>,
+    position: usize,
+    metadata: Option<Metadata>,
+    // a failed read is never followed by what looks like a clean end
+    failed: bool,
+    finished: bool,
+}
+
+impl<R: Read> DecryptingReader<R> {
+    pub fn new(inner: R, private_key: &str) -> anyhow::Result<Self> {
+        Ok(Self::with_decryptor(
+            inner,
+            AesECCCipher::new(private_key).decryptor()?,
+        ))
+    }
+
+    // For checking the signer or the curve
+    pub fn with_decryptor(inner: R, decryptor: Decryptor) -> Self {
+        DecryptingReader {
+            inner: Some(inner),
+            decryptor: Some(decryptor),
+            buffer: vec![0u8; BUFFER_SIZE],
+            plaintext: Vec::new(),
+            position: 0,
+            metadata: None,
+            failed: false,
+            finished: false,
+        }
+    }
+
+    // The metadata record, once the end of the input has been read
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_ref()
+    }
+
+    // Checks that the whole plaintext was read, so the end of the container
+    // was authenticated, and hands back the inner reader
+    pub fn finish(mut self) -> anyhow::Result<R> {
+        // an error is reported to the caller, not by drop
+        self.finished = true;
+        if self.read(&mut [0u8; 1])? != 0 {
+            return Err(anyhow::anyhow!("The plaintext was not read to the end"));
+        }
+        self.inner
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("The reader was already finished"))
+    }
+
+    // Decrypts more of the input until some plaintext is ready or the input
+    // ends
+    fn fill(&mut self) -> anyhow::Result<()> {
+        self.plaintext.clear();
+        self.position = 0;
+        while self.plaintext.is_empty() {
+            let (Some(decryptor), Some(inner)) = (self.decryptor.as_mut(), self.inner.as_mut())
+            else {
+                break;
+            };
+            let bytes_read = inner.read(&mut self.buffer)?;
+            if bytes_read == 0 {
+                if let Some(decryptor) = self.decryptor.take() {
+                    self.metadata = decryptor.finalize_into(&mut self.plaintext)?;
+                }
+                break;
+            }
+            Transform::update_into(decryptor, &self.buffer[..bytes_read], &mut self.plaintext)?;
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.failed {
+            return Err(io::Error::other("The input failed to decrypt"));
+        }
+        if self.position == self.plaintext.len() {
+            if let Err(err) = self.fill() {
+                self.failed = true;
+                return Err(io::Error::other(err));
+            }
+        }
+        let available = &self.plaintext[self.position..];
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+impl<R: Read> Drop for DecryptingReader<R> {
+    fn drop(&mut self) {
+        if !self.finished {
+            log::warn!(
+                "DecryptingReader dropped without finish(), the end of the container may not have been authenticated"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curve::Curve;
+    use std::io::BufReader;
+
+    #[test]
+    fn writer_reader_roundtrip() {
+        let pair = Curve::Secp256k1.make_key().unwrap();
+        let lines: Vec<String> = (0..20000).map(|i| format!("record {}\n", i)).collect();
+
+        let mut writer = EncryptingWriter::new(Vec::new(), &pair.public_key.to_hex()).unwrap();
+        for line in &lines {
+            writer.write_all(line.as_bytes()).unwrap();
+        }
+        let encrypted = writer.finish().unwrap();
+
+        let reader = DecryptingReader::new(&encrypted[..], &pair.private_key.to_hex()).unwrap();
+        let mut reader = BufReader::new(reader);
+        let mut decrypted = String::new();
+        reader.read_to_string(&mut decrypted).unwrap();
+        assert_eq!(decrypted, lines.concat());
+        reader.into_inner().finish().unwrap();
+
+        // without the last chunk the reader fails at the end of the input
+        let truncated = &encrypted[..encrypted.len() - 100];
+        let mut reader = DecryptingReader::new(truncated, &pair.private_key.to_hex()).unwrap();
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+        assert!(reader.finish().is_err());
+    }
+}
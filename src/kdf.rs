@@ -0,0 +1,49 @@
+use openssl::md::Md;
+use openssl::pkey::Id;
+use openssl::pkey_ctx::PkeyCtx;
+
+// HKDF-SHA256 (RFC 5869), extract and expand in one step
+pub(crate) fn hkdf_sha256(
+    ikm: &[u8],
+    salt: &[u8],
+    info: &[&[u8]],
+    out: &mut [u8],
+) -> anyhow::Result<()> {
+    let mut ctx = PkeyCtx::new_id(Id::HKDF)?;
+    ctx.derive_init()?;
+    ctx.set_hkdf_md(Md::sha256())?;
+    ctx.set_hkdf_key(ikm)?;
+    ctx.set_hkdf_salt(salt)?;
+    for part in info {
+        ctx.add_hkdf_info(part)?;
+    }
+    let len = ctx.derive(Some(out))?;
+    if len != out.len() {
+        return Err(anyhow::anyhow!(
+            "HKDF derived {} bytes, expected {}",
+            len,
+            out.len()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils;
+
+    // RFC 5869 test case 1
+    #[test]
+    fn rfc5869_sha256() {
+        let ikm = [0x0bu8; 22];
+        let salt = utils::decode_hex("000102030405060708090a0b0c").unwrap();
+        let info = utils::decode_hex("f0f1f2f3f4f5f6f7f8f9").unwrap();
+        let mut okm = [0u8; 42];
+        hkdf_sha256(&ikm, &salt, &[&info[..5], &info[5..]], &mut okm).unwrap();
+        assert_eq!(
+            utils::encode_hex(&okm),
+            "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865"
+        );
+    }
+}
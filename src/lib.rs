@@ -1,8 +1,18 @@
 mod header;
+mod kdf;
+mod stream;
 mod utils;
 
 pub mod aes_ecc;
+pub mod archive;
 pub mod cipher;
+pub mod curve;
 pub mod error;
+pub mod format;
+pub mod inspect;
+pub mod io;
+pub mod key;
+pub mod metadata;
+pub mod password;
 pub mod version;
 pub mod xor;
@@ -1,16 +1,21 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use clap_verbosity_flag::Verbosity;
+use file_cipher::cipher::{Cipher, DynCipher};
+use file_cipher::metadata::Metadata;
+use filetime::FileTime;
+use globset::{Glob, GlobSet, GlobSetBuilder};
 
-use log;
+use std::collections::BTreeMap;
 use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter};
-use std::time::Instant;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, OnceLock};
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Ok, Result};
-use file_cipher;
-use micro_uecc_safe;
+use zeroize::Zeroizing;
 
 #[derive(Parser)]
 #[command(version = "1.1.1")]
@@ -25,15 +30,286 @@ struct Cli {
     verbose: Verbosity,
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum CurveArg {
+    Secp256k1,
+    Secp256r1,
+}
+
+impl From<CurveArg> for file_cipher::curve::Curve {
+    fn from(value: CurveArg) -> Self {
+        match value {
+            CurveArg::Secp256k1 => file_cipher::curve::Curve::Secp256k1,
+            CurveArg::Secp256r1 => file_cipher::curve::Curve::Secp256r1,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SymlinkPolicy {
+    Follow,
+    Skip,
+    Error,
+}
+
+#[derive(Args)]
+struct OutputArgs {
+    #[arg(long, help = "overwrite output files that already exist")]
+    force: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "force",
+        help = "skip inputs whose output file already exists. Without --force or --no-clobber an existing output is an error"
+    )]
+    no_clobber: bool,
+}
+
+// Added to encrypted file names unless --suffix says otherwise
+const DEFAULT_SUFFIX: &str = "fc";
+
+#[derive(Args)]
+struct WalkArgs {
+    #[command(flatten)]
+    policy: OutputArgs,
+
+    #[arg(
+        long,
+        short,
+        help = "walk input directories recursively and recreate the tree under the output directory"
+    )]
+    recursive: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "skip",
+        help = "what to do with symbolic links inside input directories"
+    )]
+    symlinks: SymlinkPolicy,
+
+    #[arg(
+        long,
+        help = "only process files whose name or relative path matches this glob, can be given multiple times"
+    )]
+    include: Vec<String>,
+
+    #[arg(
+        long,
+        help = "skip files and directories whose name or relative path matches this glob, can be given multiple times. .DS_Store is skipped too unless --no-default-excludes is given"
+    )]
+    exclude: Vec<String>,
+
+    #[arg(long, help = "do not skip .DS_Store files unless --exclude names them")]
+    no_default_excludes: bool,
+
+    #[arg(
+        long,
+        short,
+        default_value = "1",
+        help = "number of files processed concurrently, 0 uses one per CPU"
+    )]
+    jobs: usize,
+
+    #[arg(
+        long,
+        help = "extension added to encrypted files and stripped again when decrypting, an empty value keeps names unchanged [default: fc]"
+    )]
+    suffix: Option<String>,
+
+    #[arg(
+        long,
+        help = "store the original file name inside encrypted files, aes and password only. Decrypting restores it even if the encrypted file was renamed"
+    )]
+    keep_name: bool,
+
+    #[arg(
+        long,
+        help = "store permissions and access/modification times inside encrypted files, aes and password only. Decrypting restores them"
+    )]
+    preserve: bool,
+
+    #[arg(
+        long,
+        requires = "preserve",
+        help = "with --preserve also store extended attributes (Unix only)"
+    )]
+    xattrs: bool,
+
+    #[arg(
+        long,
+        help = "when decrypting also restore setuid, setgid and sticky bits and extended attributes outside the user namespace. Only use this for files from a trusted sender"
+    )]
+    trust_metadata: bool,
+
+    #[arg(
+        long,
+        help = "write foo.ext.fc next to foo.ext when encrypting, foo.ext next to foo.ext.fc when decrypting"
+    )]
+    in_place: bool,
+
+    #[arg(
+        long,
+        help = "remove each input once its output is written, after checking that encrypted outputs decrypt back to it. Decrypting only removes authenticated inputs, not xor or legacy AES-ECB files. The input is overwritten before it is unlinked, on a best-effort basis"
+    )]
+    remove_source: bool,
+}
+
+impl WalkArgs {
+    fn suffix(&self) -> &str {
+        self.suffix.as_deref().unwrap_or(DEFAULT_SUFFIX)
+    }
+}
+
+// The key of the aes and archive commands, whichever way they go
+#[derive(Args)]
+struct KeyArgs {
+    #[arg(
+        long,
+        short,
+        help = "The public key generated by the generate-key command is required for encryption. The private key generated by the generate-key command is required for decryption."
+    )]
+    key: Option<String>,
+
+    #[arg(
+        long,
+        conflicts_with = "key",
+        help = "read the key from a PEM or DER file instead: a public key (SubjectPublicKeyInfo) for encryption, a private key (PKCS#8 or SEC1) for decryption"
+    )]
+    key_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "read the passphrase of protected key files from this file descriptor instead of prompting on the terminal. Protected files share the passphrase"
+    )]
+    key_passphrase_fd: Option<i32>,
+
+    #[arg(
+        long,
+        value_enum,
+        help = "curve of the key. Encryption defaults to secp256k1, decryption reads it from the input file"
+    )]
+    curve: Option<CurveArg>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "secp256k1",
+        help = "curve of the --sign-with or --verify-with key"
+    )]
+    signer_curve: CurveArg,
+}
+
+// Keys that only take part in encryption
+#[derive(Args, Default)]
+struct EncryptKeyArgs {
+    #[arg(
+        long,
+        help = "also encrypt for this public key, can be given multiple times. Any one of the matching private keys can decrypt"
+    )]
+    recipient: Vec<String>,
+
+    #[arg(
+        long,
+        help = "also encrypt for the public key in this PEM or DER file, can be given multiple times"
+    )]
+    recipient_file: Vec<PathBuf>,
+
+    #[arg(
+        long,
+        help = "sign the encrypted file with this private key generated by the generate-key command"
+    )]
+    sign_with: Option<String>,
+
+    #[arg(
+        long,
+        conflicts_with = "sign_with",
+        help = "read the --sign-with private key from a PEM or DER file instead, its curve replaces --signer-curve"
+    )]
+    sign_with_file: Option<PathBuf>,
+}
+
+// Keys that only take part in decryption
+#[derive(Args, Default)]
+struct DecryptKeyArgs {
+    #[arg(long, help = "require the input file to be signed by this public key")]
+    verify_with: Option<String>,
+
+    #[arg(
+        long,
+        conflicts_with = "verify_with",
+        help = "read the --verify-with public key from a PEM or DER file instead, its curve replaces --signer-curve"
+    )]
+    verify_with_file: Option<PathBuf>,
+}
+
 #[derive(Subcommand)]
-enum Command {
-    Xor {
-        #[arg(long, short, help = "input file path or input directory")]
+enum ArchiveCommand {
+    Create {
+        #[arg(long, short, help = "directory to pack")]
+        input: PathBuf,
+
+        #[arg(long, short, help = "archive file to write, - writes to stdout")]
+        output: PathBuf,
+
+        #[command(flatten)]
+        policy: OutputArgs,
+
+        #[command(flatten)]
+        key: KeyArgs,
+
+        #[command(flatten)]
+        encrypt_keys: EncryptKeyArgs,
+    },
+
+    List {
+        #[arg(long, short, help = "archive file, - reads from stdin")]
+        input: PathBuf,
+
+        #[command(flatten)]
+        key: KeyArgs,
+
+        #[command(flatten)]
+        decrypt_keys: DecryptKeyArgs,
+    },
+
+    Extract {
+        #[arg(long, short, help = "archive file, - reads from stdin")]
         input: PathBuf,
 
         #[arg(long, short, help = "output directory")]
         output: PathBuf,
 
+        #[arg(help = "only extract this path and what is below it, as printed by list")]
+        path: Option<PathBuf>,
+
+        #[command(flatten)]
+        key: KeyArgs,
+
+        #[command(flatten)]
+        decrypt_keys: DecryptKeyArgs,
+    },
+}
+
+#[derive(Subcommand)]
+enum Command {
+    Xor {
+        #[arg(
+            long,
+            short,
+            help = "input file path or input directory, - reads a single stream from stdin"
+        )]
+        input: PathBuf,
+
+        #[arg(
+            long,
+            short,
+            required_unless_present = "in_place",
+            conflicts_with = "in_place",
+            help = "output directory, - writes to stdout. With -i - this is the output file"
+        )]
+        output: Option<PathBuf>,
+
         #[arg(
             long,
             short,
@@ -42,6 +318,9 @@ enum Command {
         )]
         decrypt: bool,
 
+        #[command(flatten)]
+        walk: WalkArgs,
+
         #[arg(
             long,
             short,
@@ -51,11 +330,21 @@ enum Command {
     },
 
     Aes {
-        #[arg(long, short, help = "input file path or input directory")]
+        #[arg(
+            long,
+            short,
+            help = "input file path or input directory, - reads a single stream from stdin"
+        )]
         input: PathBuf,
 
-        #[arg(long, short, help = "output directory")]
-        output: PathBuf,
+        #[arg(
+            long,
+            short,
+            required_unless_present = "in_place",
+            conflicts_with = "in_place",
+            help = "output directory, - writes to stdout. With -i - this is the output file"
+        )]
+        output: Option<PathBuf>,
 
         #[arg(
             long,
@@ -65,137 +354,2125 @@ enum Command {
         )]
         decrypt: bool,
 
+        #[command(flatten)]
+        walk: WalkArgs,
+
+        #[command(flatten)]
+        key: KeyArgs,
+
+        #[command(flatten)]
+        encrypt_keys: EncryptKeyArgs,
+
+        #[command(flatten)]
+        decrypt_keys: DecryptKeyArgs,
+
+        #[arg(
+            long,
+            help = "private key file used to check that encrypted files decrypt before --remove-source deletes them"
+        )]
+        roundtrip_key_file: Option<PathBuf>,
+    },
+
+    #[command(about = "pack a directory into a single encrypted container")]
+    Archive {
+        #[command(subcommand)]
+        command: ArchiveCommand,
+    },
+
+    Password {
+        #[arg(
+            long,
+            short,
+            help = "input file path or input directory, - reads a single stream from stdin"
+        )]
+        input: PathBuf,
+
+        #[arg(
+            long,
+            short,
+            required_unless_present = "in_place",
+            conflicts_with = "in_place",
+            help = "output directory, - writes to stdout. With -i - this is the output file"
+        )]
+        output: Option<PathBuf>,
+
         #[arg(
             long,
             short,
-            help = "The public key generated by the generate-key command is required for encryption. The private key generated by the generate-key command is required for decryption."
+            default_value = "false",
+            help = "decrypt the input file. The default value is false, which is encrypted"
+        )]
+        decrypt: bool,
+
+        #[command(flatten)]
+        walk: WalkArgs,
+
+        #[arg(
+            long,
+            help = "read the passphrase from this file descriptor instead of prompting on the terminal"
         )]
-        key: String,
+        passphrase_fd: Option<i32>,
+
+        #[arg(
+            long,
+            default_value = "17",
+            help = "scrypt cost when encrypting, N = 2^scrypt_log_n. Decryption reads it from the input file"
+        )]
+        scrypt_log_n: u8,
     },
 
-    GenerateKey,
-}
+    GenerateKey {
+        #[arg(
+            long,
+            value_enum,
+            default_value = "secp256k1",
+            help = "curve of the key pair"
+        )]
+        curve: CurveArg,
 
-fn processing_file<C: file_cipher::cipher::Cipher, P: AsRef<std::path::Path>>(
-    cipher: &C,
-    input: P,
-    output: P,
-    encrypt: bool,
-) -> Result<()> {
-    let input = input.as_ref();
-    let output = output.as_ref();
-    let in_file = File::open(input)?;
+        #[arg(
+            long,
+            help = "write private_key.pem (PKCS#8) and public_key.pem (SubjectPublicKeyInfo) into this directory instead of printing hex"
+        )]
+        out: Option<PathBuf>,
 
-    let mut br = BufReader::new(in_file);
-    let mut bw: BufWriter<File>;
+        #[arg(
+            long,
+            requires = "out",
+            help = "encrypt private_key.pem with a scrypt derived key. Only this tool reads it, OpenSSL encrypted keys are read too"
+        )]
+        protect: bool,
 
-    let title = if encrypt { "encrypt" } else { "decrypt" };
-    log::info!("{} input file: {}", title, input.to_str().unwrap());
-    log::info!("{} output file: {}", title, output.to_str().unwrap());
-    let begin_time = Instant::now();
-    if encrypt {
-        let out_file = OpenOptions::new().create(true).write(true).open(output)?;
-        bw = BufWriter::new(out_file);
-        cipher.encrypt(&mut br, &mut bw)?;
-    } else {
-        let out_file = OpenOptions::new().create(true).write(true).open(output)?;
-        bw = BufWriter::new(out_file);
-        cipher.decrypt(&mut br, &mut bw)?;
-    }
+        #[arg(
+            long,
+            requires = "protect",
+            help = "read the passphrase of the new key from this file descriptor instead of prompting on the terminal"
+        )]
+        key_passphrase_fd: Option<i32>,
+    },
 
-    let end_time = Instant::now();
-    log::trace!("elapsed time: {:?}", end_time.duration_since(begin_time));
-    Ok(())
+    #[command(about = "decrypt any container, the format is read from each input file")]
+    Decrypt {
+        #[arg(
+            long,
+            short,
+            help = "input file path or input directory, - reads a single stream from stdin"
+        )]
+        input: PathBuf,
+
+        #[arg(
+            long,
+            short,
+            required_unless_present = "in_place",
+            conflicts_with = "in_place",
+            help = "output directory, - writes to stdout. With -i - this is the output file"
+        )]
+        output: Option<PathBuf>,
+
+        #[command(flatten)]
+        walk: WalkArgs,
+
+        #[arg(long, short, help = "the xor value used for xor encrypted files")]
+        xor: Option<u8>,
+
+        #[arg(
+            long,
+            short,
+            help = "private key for aes encrypted files, generated by the generate-key command"
+        )]
+        key: Option<String>,
+
+        #[arg(
+            long,
+            conflicts_with = "key",
+            help = "read the private key for aes encrypted files from a PEM or DER file instead"
+        )]
+        key_file: Option<PathBuf>,
+
+        #[arg(
+            long,
+            requires = "key_file",
+            help = "read the passphrase of a protected --key-file from this file descriptor instead of prompting on the terminal"
+        )]
+        key_passphrase_fd: Option<i32>,
+
+        #[arg(
+            long,
+            help = "read the passphrase of password encrypted files from this file descriptor instead of prompting on the terminal"
+        )]
+        passphrase_fd: Option<i32>,
+
+        #[arg(
+            long,
+            help = "require aes encrypted files to be signed by this public key"
+        )]
+        verify_with: Option<String>,
+
+        #[arg(
+            long,
+            conflicts_with = "verify_with",
+            help = "read the --verify-with public key from a PEM or DER file instead, its curve replaces --signer-curve"
+        )]
+        verify_with_file: Option<PathBuf>,
+
+        #[arg(
+            long,
+            value_enum,
+            default_value = "secp256k1",
+            help = "curve of the --verify-with key"
+        )]
+        signer_curve: CurveArg,
+    },
+
+    #[command(about = "show what a container header says without decrypting it")]
+    Inspect {
+        #[arg(help = "file to inspect, - reads stdin")]
+        input: PathBuf,
+
+        #[arg(long, help = "print a JSON object instead of text")]
+        json: bool,
+    },
 }
 
-fn processing<C: file_cipher::cipher::Cipher>(
-    cipher: &C,
-    input: &PathBuf,
-    output: &PathBuf,
-    encrypt: bool,
-) -> anyhow::Result<()> {
-    if !output.exists() {
-        let copied = output.clone();
-        std::fs::create_dir_all(copied.as_path())?;
-        log::info!("Create output directory: {}", &copied.to_str().unwrap());
+// The descriptor is borrowed, not closed, and 0 to 2 are refused since they
+// may carry -i - or -o - data
+#[cfg(unix)]
+fn read_passphrase_fd(fd: i32) -> Result<Zeroizing<String>> {
+    use std::io::Read;
+    use std::mem::ManuallyDrop;
+    use std::os::fd::FromRawFd;
+
+    if fd <= 2 {
+        return Err(anyhow!(
+            "The passphrase descriptor {} is stdin, stdout or stderr, use 3 or above",
+            fd
+        ));
     }
+    // SAFETY: the descriptor is handed to us on the command line only to read
+    // the passphrase from, and ManuallyDrop keeps it open afterwards.
+    let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    let mut passphrase = Zeroizing::new(String::new());
+    file.read_to_string(&mut passphrase)?;
+    Ok(Zeroizing::new(
+        passphrase.lines().next().unwrap_or_default().to_owned(),
+    ))
+}
 
-    if input.is_dir() {
-        for entry in std::fs::read_dir(input)? {
-            let entry = entry?;
-            let path = entry.path();
+#[cfg(not(unix))]
+fn read_passphrase_fd(_fd: i32) -> Result<Zeroizing<String>> {
+    Err(anyhow!("Passphrase descriptors are only supported on unix"))
+}
 
-            if path.is_dir() {
-                continue;
+fn read_passphrase(fd: Option<i32>, confirm: bool) -> Result<Zeroizing<String>> {
+    let passphrase = match fd {
+        Some(fd) => read_passphrase_fd(fd)?,
+        None => {
+            let passphrase = Zeroizing::new(rpassword::prompt_password("Passphrase: ")?);
+            if confirm
+                && *Zeroizing::new(rpassword::prompt_password("Confirm passphrase: ")?)
+                    != *passphrase
+            {
+                return Err(anyhow!("The passphrases do not match"));
             }
+            passphrase
+        }
+    };
+    if passphrase.is_empty() {
+        return Err(anyhow!("The passphrase cannot be empty"));
+    }
+    Ok(passphrase)
+}
 
-            if path.ends_with(".DS_Store") {
-                continue;
-            }
+// The passphrase of protected key files. A descriptor can only be read once,
+// so every key file of a command shares what it held, and it is wiped when
+// the command has read its keys.
+struct KeyPassphrase {
+    fd: Option<i32>,
+    read: Option<Zeroizing<String>>,
+}
 
-            let filename = path.file_name().unwrap();
-            let mut _output = output.clone();
-            _output.push(filename);
-            match processing_file(cipher, path, _output.clone(), encrypt) {
-                Err(ref err) => {
-                    log::error!("{}", err);
-                    std::fs::remove_file(_output)?;
-                }
-                _ => {}
-            }
+impl KeyPassphrase {
+    fn new(fd: Option<i32>) -> Self {
+        KeyPassphrase { fd, read: None }
+    }
+
+    fn get(&mut self) -> Result<Zeroizing<String>> {
+        if self.fd.is_none() {
+            return read_passphrase(None, false);
         }
-    } else {
-        let filename = input.file_name().unwrap();
-        let mut _output = output.clone();
-        _output.push(filename);
-        match processing_file(cipher, input, &_output.clone(), encrypt) {
-            Err(ref err) => {
-                log::error!("{}", err);
-                std::fs::remove_file(_output)?;
-            }
-            _ => {}
+        if self.read.is_none() {
+            self.read = Some(read_passphrase(self.fd, false)?);
         }
+        Ok(self.read.clone().unwrap_or_default())
     }
+}
+
+// Private keys are only readable by the owner
+fn create_key_file(path: &Path, private: bool) -> Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    options
+        .open(path)
+        .map_err(|err| anyhow!("{}: {}", path.display(), err))
+}
 
+fn write_key_files(
+    dir: &Path,
+    curve: file_cipher::curve::Curve,
+    passphrase: Option<Zeroizing<String>>,
+) -> Result<()> {
+    use std::io::Write;
+
+    let key = curve.make_key()?;
+    let private_pem = match passphrase {
+        Some(passphrase) => file_cipher::key::private_key_to_protected_pem(
+            curve,
+            &key.private_key,
+            passphrase.as_bytes(),
+        )?,
+        None => file_cipher::key::private_key_to_pem(curve, &key.private_key)?,
+    };
+    std::fs::create_dir_all(dir)?;
+    let private_path = dir.join("private_key.pem");
+    let public_path = dir.join("public_key.pem");
+    create_key_file(&private_path, true)?.write_all(&private_pem)?;
+    create_key_file(&public_path, false)?.write_all(&file_cipher::key::public_key_to_pem(
+        curve,
+        &key.public_key,
+    )?)?;
+    println!("private_key: {}", private_path.display());
+    println!("public_key: {}", public_path.display());
     Ok(())
 }
-fn main() -> Result<()> {
-    let cli = Cli::parse();
 
-    env_logger::Builder::new()
-        .filter_level(cli.verbose.log_level_filter())
-        .init();
+// Resolve a private key file to the hex key and curve the cipher expects
+fn read_private_key_file(
+    path: &Path,
+    passphrase: &mut KeyPassphrase,
+) -> Result<(String, file_cipher::curve::Curve)> {
+    let bytes = std::fs::read(path)?;
+    let (curve, key) = if file_cipher::key::is_protected(&bytes) {
+        file_cipher::key::read_protected_private_key(&bytes, passphrase.get()?.as_bytes())?
+    } else {
+        file_cipher::key::read_private_key(&bytes)?
+    };
+    Ok((key.to_hex(), curve))
+}
 
-    match cli.command {
-        Command::GenerateKey => {
-            let key = micro_uecc_safe::uecc_mkae_key_with_secp256k1()?;
-            println!("private_key: {}", key.private_key);
-            println!("public_key: {}", key.public_key);
-            return Ok(());
+fn read_public_key_file(path: &Path) -> Result<(String, file_cipher::curve::Curve)> {
+    let (curve, key) = file_cipher::key::read_public_key(&std::fs::read(path)?)?;
+    Ok((key.to_hex(), curve))
+}
+
+impl KeyArgs {
+    // The --recipient and --sign-with keys of `encrypt_keys` only apply when
+    // encrypting and the --verify-with keys of `decrypt_keys` when decrypting
+    fn cipher(
+        self,
+        decrypt: bool,
+        encrypt_keys: EncryptKeyArgs,
+        decrypt_keys: DecryptKeyArgs,
+        passphrase: &mut KeyPassphrase,
+    ) -> Result<file_cipher::aes_ecc::AesECCCipher> {
+        if decrypt && !(encrypt_keys.recipient.is_empty() && encrypt_keys.recipient_file.is_empty())
+        {
+            return Err(anyhow!("--recipient can only be used when encrypting"));
+        }
+        if decrypt && (encrypt_keys.sign_with.is_some() || encrypt_keys.sign_with_file.is_some()) {
+            return Err(anyhow!("--sign-with can only be used when encrypting"));
+        }
+        if !decrypt
+            && (decrypt_keys.verify_with.is_some() || decrypt_keys.verify_with_file.is_some())
+        {
+            return Err(anyhow!("--verify-with can only be used when decrypting"));
         }
-        Command::Xor {
-            input,
-            output,
-            decrypt,
-            xor,
-        } => {
-            if xor == 0 {
-                log::error!("The xor parameter cannot be zero");
-                return Err(anyhow!("The xor parameter cannot be zero"));
-            }
 
-            let cipher = file_cipher::xor::XorCipher::new(xor);
-            processing(&cipher, &input, &output, !decrypt)?;
+        let mut curve = self.curve.map(file_cipher::curve::Curve::from);
+        let key = match self.key_file {
+            Some(path) => {
+                let (key, file_curve) = if decrypt {
+                    read_private_key_file(&path, passphrase)?
+                } else {
+                    read_public_key_file(&path)?
+                };
+                if curve.is_some_and(|curve| curve != file_curve) {
+                    return Err(anyhow!(
+                        "--curve does not match the {} key in {}",
+                        file_curve,
+                        path.display()
+                    ));
+                }
+                curve = Some(file_curve);
+                Some(key)
+            }
+            None => self.key,
+        };
+        let mut recipients = encrypt_keys.recipient;
+        for path in &encrypt_keys.recipient_file {
+            let (public_key, file_curve) = read_public_key_file(path)?;
+            if curve.is_some_and(|curve| curve != file_curve) {
+                return Err(anyhow!(
+                    "The {} key in {} does not match the curve of the other keys",
+                    file_curve,
+                    path.display()
+                ));
+            }
+            curve = Some(file_curve);
+            recipients.push(public_key);
         }
-        Command::Aes {
-            input,
-            output,
-            decrypt,
-            key,
-        } => {
-            let cipher = file_cipher::aes_ecc::AesECCCipher::new(&key);
-            processing(&cipher, &input, &output, !decrypt)?;
+        let mut keys = key.into_iter().chain(recipients);
+        let key = keys.next().ok_or_else(|| {
+            if decrypt {
+                anyhow!("--key or --key-file is required")
+            } else {
+                anyhow!("--key, --key-file, --recipient or --recipient-file is required")
+            }
+        })?;
+        let mut cipher = match curve {
+            Some(curve) => file_cipher::aes_ecc::AesECCCipher::with_curve(&key, curve),
+            None => file_cipher::aes_ecc::AesECCCipher::new(&key),
+        };
+        for public_key in keys {
+            cipher = cipher.add_recipient(&public_key);
+        }
+        if let Some(private_key) = encrypt_keys.sign_with {
+            cipher = cipher.sign_with(&private_key, self.signer_curve.into());
+        }
+        if let Some(path) = encrypt_keys.sign_with_file {
+            let (private_key, curve) = read_private_key_file(&path, passphrase)?;
+            cipher = cipher.sign_with(&private_key, curve);
+        }
+        if let Some(public_key) = decrypt_keys.verify_with {
+            cipher = cipher.verify_with(&public_key, self.signer_curve.into());
+        }
+        if let Some(path) = decrypt_keys.verify_with_file {
+            let (public_key, curve) = read_public_key_file(&path)?;
+            cipher = cipher.verify_with(&public_key, curve);
+        }
+        Ok(cipher)
+    }
+}
+
+// Output is written to a temp file in the target directory and only renamed
+// over the target once complete, an error or a drop leaves the target alone.
+struct AtomicOutput {
+    path: PathBuf,
+    temp: PathBuf,
+    writer: Option<BufWriter<File>>,
+}
+
+impl AtomicOutput {
+    fn create(path: &Path) -> Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let name = path
+            .file_name()
+            .ok_or_else(|| anyhow!("Invalid output file: {}", path.display()))?;
+        let temp = path.with_file_name(format!(
+            ".{}.{}-{}.tmp",
+            name.to_string_lossy(),
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp)?;
+        Ok(AtomicOutput {
+            path: path.to_path_buf(),
+            temp,
+            writer: Some(BufWriter::new(file)),
+        })
+    }
+
+    fn writer(&mut self) -> &mut BufWriter<File> {
+        // only taken by commit
+        self.writer.as_mut().unwrap()
+    }
+
+    // Applied to the temporary file, so the output appears complete with its
+    // metadata. Nothing may be written afterwards.
+    fn apply_metadata(&mut self, metadata: &Metadata, trusted: bool) -> Result<()> {
+        self.writer().flush()?;
+        apply_metadata(&self.temp, metadata, trusted)
+    }
+
+    fn commit(mut self, policy: &OutputArgs) -> Result<()> {
+        let file = self.writer.take().unwrap().into_inner()?;
+        file.sync_all()?;
+        drop(file);
+        check_output(&self.path, policy)?;
+        std::fs::rename(&self.temp, &self.path)?;
+        #[cfg(unix)]
+        if let Some(dir) = self.path.parent() {
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AtomicOutput {
+    fn drop(&mut self) {
+        if self.temp.exists() {
+            self.writer.take();
+            let _ = std::fs::remove_file(&self.temp);
+        }
+    }
+}
+
+// Err when the output exists and may not be replaced. --no-clobber is handled
+// by the callers before any work is done.
+fn check_output(path: &Path, policy: &OutputArgs) -> Result<()> {
+    if !policy.force && path.exists() {
+        return Err(anyhow!(
+            "{} already exists, use --force to overwrite it",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+fn open_input(path: &Path) -> Result<Box<dyn Read>> {
+    if is_stdio(path) {
+        Ok(Box::new(std::io::stdin().lock()))
+    } else {
+        Ok(Box::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+// A single input streamed to a single output, either side may be stdio.
+// Logging goes to stderr and nothing else is printed, so stdout only ever
+// carries the cipher output.
+fn processing_stream(
+    cipher: &dyn DynCipher,
+    input: &Path,
+    output: &Path,
+    encrypt: bool,
+    policy: &OutputArgs,
+) -> Result<()> {
+    if input.is_dir() {
+        return Err(anyhow!(
+            "{} is a directory, streaming needs a single input",
+            input.display()
+        ));
+    }
+    let mut reader = open_input(input)?;
+    let mut stdout = BufWriter::new(std::io::stdout().lock());
+    stream_to(cipher, &mut reader, output, encrypt, policy, &mut stdout)
+}
+
+// `stdout` takes the output when it is -, else it goes to a file
+fn stream_to(
+    cipher: &dyn DynCipher,
+    reader: &mut dyn Read,
+    output: &Path,
+    encrypt: bool,
+    policy: &OutputArgs,
+    stdout: &mut dyn Write,
+) -> Result<()> {
+    if is_stdio(output) {
+        return if encrypt {
+            cipher.encrypt_dyn(reader, stdout)
+        } else {
+            cipher.decrypt_dyn(reader, stdout)
+        };
+    }
+
+    if policy.no_clobber && output.exists() {
+        log::warn!("skipped, {} already exists", output.display());
+        return Ok(());
+    }
+    check_output(output, policy)?;
+    let mut atomic = AtomicOutput::create(output)?;
+    if encrypt {
+        cipher.encrypt_dyn(reader, atomic.writer())?;
+    } else {
+        cipher.decrypt_dyn(reader, atomic.writer())?;
+    }
+    atomic.commit(policy)
+}
+
+// Picks the cipher for each input from its container format. The password
+// is only asked for once the first password encrypted input shows up.
+struct AutoCipher {
+    xor: Option<file_cipher::xor::XorCipher>,
+    aes: Option<file_cipher::aes_ecc::AesECCCipher>,
+    passphrase_fd: Option<i32>,
+    password: OnceLock<std::result::Result<file_cipher::password::PasswordCipher, String>>,
+}
+
+impl AutoCipher {
+    fn password(&self) -> Result<&file_cipher::password::PasswordCipher> {
+        self.password
+            .get_or_init(|| {
+                read_passphrase(self.passphrase_fd, false)
+                    .map(|passphrase| file_cipher::password::PasswordCipher::new(&passphrase))
+                    .map_err(|err| {
+                        format!(
+                            "password encrypted, enter the passphrase on a terminal or pass --passphrase-fd: {}",
+                            err
+                        )
+                    })
+            })
+            .as_ref()
+            .map_err(|err| anyhow!("{}", err))
+    }
+}
+
+impl Cipher for AutoCipher {
+    fn encrypt<R: Read, W: Write>(&self, _src: &mut R, _dst: &mut W) -> Result<()> {
+        Err(anyhow!("The decrypt command cannot encrypt"))
+    }
+
+    fn decrypt<R: Read, W: Write>(&self, src: &mut R, dst: &mut W) -> Result<()> {
+        self.decrypt_with_metadata(src, dst).map(|_| ())
+    }
+
+    fn decrypt_with_metadata<R: Read, W: Write>(
+        &self,
+        src: &mut R,
+        dst: &mut W,
+    ) -> Result<Option<Metadata>> {
+        use file_cipher::format::Algorithm;
+        use file_cipher::version::Version;
+
+        let mut prefix = Vec::with_capacity(file_cipher::inspect::PREFIX_LEN);
+        src.take(file_cipher::inspect::PREFIX_LEN as u64)
+            .read_to_end(&mut prefix)?;
+        if !prefix.starts_with(file_cipher::format::MAGIC) {
+            return Err(anyhow!(
+                "The input file is not a file encrypted by file_cipher"
+            ));
+        }
+        let detected = file_cipher::inspect::detect(&prefix)?;
+        let mut src = (&prefix[..]).chain(src);
+        match detected {
+            (Version::V1, _) => self
+                .xor
+                .as_ref()
+                .ok_or_else(|| anyhow!("xor encrypted, pass the xor value with --xor"))?
+                .decrypt_with_metadata(&mut src, dst),
+            (Version::V2 | Version::V3, _) | (Version::V5, Some(Algorithm::Ecies)) => self
+                .aes
+                .as_ref()
+                .ok_or_else(|| {
+                    anyhow!("aes encrypted, pass the private key with --key or --key-file")
+                })?
+                .decrypt_with_metadata(&mut src, dst),
+            (Version::V4, _) | (Version::V5, _) => {
+                self.password()?.decrypt_with_metadata(&mut src, dst)
+            }
+        }
+    }
+}
+
+fn inspect(input: &Path, json: bool) -> Result<()> {
+    let mut reader = open_input(input)?;
+    let Some(info) = file_cipher::inspect::inspect(&mut reader)? else {
+        if json {
+            println!("{}", serde_json::json!({"container": false}));
+        }
+        return Err(anyhow!(
+            "{} is not a file_cipher container",
+            input.display()
+        ));
+    };
+
+    let curve = info.curve.map(|curve| curve.name());
+    let scrypt = info
+        .scrypt
+        .map(|params| format!("log_n={} r={} p={}", params.log_n, params.r, params.p));
+    if !json {
+        println!("format:         {}", info.format);
+        println!("algorithm:      {}", info.algorithm);
+        let fields = [
+            ("curve", curve.map(str::to_owned)),
+            ("recipients", info.recipients.map(|count| count.to_string())),
+            ("chunk size", info.chunk_size.map(|size| size.to_string())),
+            ("iv", info.iv),
+            ("salt", info.salt),
+            ("scrypt", scrypt),
+        ];
+        for (name, value) in fields {
+            if let Some(value) = value {
+                println!("{:<15} {}", format!("{}:", name), value);
+            }
+        }
+        for key in &info.ephemeral_keys {
+            println!("ephemeral key:  {}", key);
+        }
+        let yes_no = |value: bool| if value { "yes" } else { "no" };
+        if let Some(signed) = info.signed {
+            println!("signed:         {}", yes_no(signed));
+        }
+        if let Some(metadata) = info.metadata {
+            println!("metadata:       {}", yes_no(metadata));
+        }
+        return Ok(());
+    }
+
+    let scrypt = info
+        .scrypt
+        .map(|params| serde_json::json!({"log_n": params.log_n, "r": params.r, "p": params.p}));
+    let json = serde_json::json!({
+        "container": true,
+        "format": info.format,
+        "algorithm": info.algorithm,
+        "curve": curve,
+        "ephemeral_keys": info.ephemeral_keys,
+        "iv": info.iv,
+        "salt": info.salt,
+        "chunk_size": info.chunk_size,
+        "recipients": info.recipients,
+        "signed": info.signed,
+        "metadata": info.metadata,
+        "scrypt": scrypt,
+    });
+    println!("{}", json);
+    Ok(())
+}
+
+fn archive(command: ArchiveCommand) -> Result<()> {
+    match command {
+        ArchiveCommand::Create {
+            input,
+            output,
+            policy,
+            key,
+            encrypt_keys,
+        } => {
+            let mut passphrase = KeyPassphrase::new(key.key_passphrase_fd);
+            let cipher = key.cipher(
+                false,
+                encrypt_keys,
+                DecryptKeyArgs::default(),
+                &mut passphrase,
+            )?;
+            drop(passphrase);
+            if is_stdio(&output) {
+                let mut writer = BufWriter::new(std::io::stdout().lock());
+                file_cipher::archive::create(&cipher, &input, &mut writer)?;
+                writer.flush()?;
+                return Ok(());
+            }
+            if policy.no_clobber && output.exists() {
+                eprintln!("skipped  {} (output exists)", output.display());
+                return Ok(());
+            }
+            check_output(&output, &policy)?;
+            let mut atomic = AtomicOutput::create(&output)?;
+            file_cipher::archive::create(&cipher, &input, atomic.writer())?;
+            atomic.commit(&policy)?;
+            eprintln!("archive: {}", output.display());
+        }
+        ArchiveCommand::List {
+            input,
+            key,
+            decrypt_keys,
+        } => {
+            let mut passphrase = KeyPassphrase::new(key.key_passphrase_fd);
+            let cipher = key.cipher(
+                true,
+                EncryptKeyArgs::default(),
+                decrypt_keys,
+                &mut passphrase,
+            )?;
+            drop(passphrase);
+            let mut br = open_input(&input)?;
+            for entry in file_cipher::archive::list(&cipher, &mut br)? {
+                println!(
+                    "{}{:04o} {:>12} {:>12} {}",
+                    if entry.is_dir { "d" } else { "-" },
+                    entry.mode & 0o7777,
+                    entry.size,
+                    entry.mtime,
+                    entry.path.display()
+                );
+            }
+        }
+        ArchiveCommand::Extract {
+            input,
+            output,
+            path,
+            key,
+            decrypt_keys,
+        } => {
+            let mut passphrase = KeyPassphrase::new(key.key_passphrase_fd);
+            let cipher = key.cipher(
+                true,
+                EncryptKeyArgs::default(),
+                decrypt_keys,
+                &mut passphrase,
+            )?;
+            drop(passphrase);
+            let mut br = open_input(&input)?;
+            let extracted =
+                file_cipher::archive::extract(&cipher, &mut br, &output, path.as_deref())?;
+            eprintln!("{} entries extracted to {}", extracted, output.display());
+        }
+    }
+    Ok(())
+}
+
+// What --keep-name, --preserve and --xattrs store for `input`
+fn stored_metadata(input: &Path, args: &WalkArgs) -> Result<Option<Metadata>> {
+    if !args.keep_name && !args.preserve {
+        return Ok(None);
+    }
+    let mut metadata = Metadata::default();
+    if args.keep_name {
+        let name = input
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("The file name of {} is not UTF-8", input.display()))?;
+        metadata.name = Some(name.to_owned());
+    }
+    if args.preserve {
+        let file = std::fs::metadata(input)?;
+        metadata.mtime = file.modified().ok();
+        metadata.atime = file.accessed().ok();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            metadata.mode = Some(file.permissions().mode() & 0o7777);
+        }
+    }
+    #[cfg(unix)]
+    if args.xattrs {
+        for name in xattr::list(input)? {
+            let Some(value) = xattr::get(input, &name)? else {
+                continue;
+            };
+            match name.into_string() {
+                std::result::Result::Ok(name) => metadata.xattrs.push((name, value)),
+                Err(name) => {
+                    log::warn!("{}: skipped extended attribute {:?}", input.display(), name)
+                }
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    if args.xattrs {
+        return Err(anyhow!("--xattrs is only supported on Unix"));
+    }
+    Ok(Some(metadata))
+}
+
+// Extended attributes go first, a read-only mode would refuse them, and times
+// last so nothing else touches them afterwards. Attributes the filesystem does
+// not take are only warned about. Unless `trusted`, the metadata comes from
+// whoever encrypted the file, so the special mode bits are dropped and only
+// user.* attributes are restored.
+fn apply_metadata(path: &Path, metadata: &Metadata, trusted: bool) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        for (name, value) in &metadata.xattrs {
+            if !trusted && !name.starts_with("user.") {
+                log::warn!(
+                    "{}: extended attribute {} not restored, see --trust-metadata",
+                    path.display(),
+                    name
+                );
+                continue;
+            }
+            if let Err(err) = xattr::set(path, name, value) {
+                log::warn!(
+                    "{}: extended attribute {} not restored: {}",
+                    path.display(),
+                    name,
+                    err
+                );
+            }
+        }
+        if let Some(mode) = metadata.mode {
+            let mode = if trusted { mode & 0o7777 } else { mode & 0o777 };
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = trusted;
+    if metadata.mtime.is_some() || metadata.atime.is_some() {
+        let file = std::fs::metadata(path)?;
+        let atime = metadata
+            .atime
+            .map_or_else(|| FileTime::from_last_access_time(&file), FileTime::from);
+        let mtime = metadata.mtime.map_or_else(
+            || FileTime::from_last_modification_time(&file),
+            FileTime::from,
+        );
+        filetime::set_file_times(path, atime, mtime)?;
+    }
+    Ok(())
+}
+
+// A stored name comes out of the encrypted file, only a plain file name is
+// accepted so it cannot point outside the output directory.
+fn restored_output(output: &Path, name: &str) -> Result<PathBuf> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(std::path::is_separator) {
+        return Err(anyhow!("The stored file name {:?} is not usable", name));
+    }
+    Ok(output.with_file_name(name))
+}
+
+// Runs on a worker thread, logging of the outcome is left to the caller so it
+// stays in input order. Ok(None) when --no-clobber skipped the file, else the
+// path written, which differs from `output` when a stored name was restored.
+fn processing_file(
+    cipher: &dyn DynCipher,
+    input: &Path,
+    output: &Path,
+    encrypt: bool,
+    args: &WalkArgs,
+) -> Result<Option<(PathBuf, Duration)>> {
+    let policy = &args.policy;
+    if policy.no_clobber && output.exists() {
+        return Ok(None);
+    }
+    check_output(output, policy)?;
+    // read before opening the input, which may update its access time
+    let stored = if encrypt {
+        stored_metadata(input, args)?
+    } else {
+        None
+    };
+    let in_file = File::open(input)?;
+
+    let mut br = BufReader::new(in_file);
+
+    let begin_time = Instant::now();
+    let mut atomic = AtomicOutput::create(output)?;
+    match stored {
+        Some(metadata) => cipher.encrypt_with_metadata_dyn(&metadata, &mut br, atomic.writer())?,
+        None if encrypt => cipher.encrypt_dyn(&mut br, atomic.writer())?,
+        None => {
+            if let Some(metadata) = cipher.decrypt_with_metadata_dyn(&mut br, atomic.writer())? {
+                if let Some(name) = &metadata.name {
+                    atomic.path = restored_output(output, name)?;
+                    if policy.no_clobber && atomic.path.exists() {
+                        return Ok(None);
+                    }
+                }
+                atomic.apply_metadata(&metadata, args.trust_metadata)?;
+            }
+        }
+    }
+    let written = atomic.path.clone();
+    atomic.commit(policy)?;
+
+    let end_time = Instant::now();
+    Ok(Some((written, end_time.duration_since(begin_time))))
+}
+
+// Added to --exclude unless --no-default-excludes is given
+const DEFAULT_EXCLUDES: &[&str] = &[".DS_Store"];
+
+struct Filter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl Filter {
+    fn new(args: &WalkArgs) -> Result<Self> {
+        fn build(patterns: &[String]) -> Result<GlobSet> {
+            let mut builder = GlobSetBuilder::new();
+            for pattern in patterns {
+                builder.add(Glob::new(pattern)?);
+            }
+            Ok(builder.build()?)
+        }
+
+        let include = if args.include.is_empty() {
+            None
+        } else {
+            Some(build(&args.include)?)
+        };
+        let mut exclude = args.exclude.clone();
+        if !args.no_default_excludes {
+            exclude.extend(DEFAULT_EXCLUDES.iter().map(|pattern| pattern.to_string()));
+        }
+        Ok(Filter {
+            include,
+            exclude: build(&exclude)?,
+        })
+    }
+
+    fn matches(set: &GlobSet, relative: &Path) -> bool {
+        set.is_match(relative) || relative.file_name().is_some_and(|name| set.is_match(name))
+    }
+
+    fn excluded(&self, relative: &Path) -> bool {
+        Self::matches(&self.exclude, relative)
+    }
+
+    fn included(&self, relative: &Path) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|include| Self::matches(include, relative))
+    }
+}
+
+struct WalkFile {
+    path: PathBuf,
+    relative: PathBuf,
+    // relative to the output directory
+    output: PathBuf,
+}
+
+// Everything found under the input, paths relative to the input directory
+#[derive(Default)]
+struct Walk {
+    files: Vec<WalkFile>,
+    dirs: Vec<PathBuf>,
+    skipped: Vec<(PathBuf, &'static str)>,
+}
+
+impl Walk {
+    fn new(input: &Path, args: &WalkArgs) -> Result<Self> {
+        let filter = Filter::new(args)?;
+        let mut walk = Walk::default();
+        if input.is_dir() {
+            let mut ancestors = vec![input.canonicalize()?];
+            walk.collect(input, Path::new(""), args, &filter, &mut ancestors)?;
+        } else {
+            let filename = input
+                .file_name()
+                .ok_or_else(|| anyhow!("Invalid input file: {}", input.display()))?;
+            walk.files.push(WalkFile {
+                path: input.to_path_buf(),
+                relative: PathBuf::from(filename),
+                output: PathBuf::from(filename),
+            });
+        }
+        Ok(walk)
+    }
+
+    fn collect(
+        &mut self,
+        dir: &Path,
+        relative: &Path,
+        args: &WalkArgs,
+        filter: &Filter,
+        ancestors: &mut Vec<PathBuf>,
+    ) -> Result<()> {
+        let mut entries = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let path = entry.path();
+            let relative = relative.join(entry.file_name());
+            if filter.excluded(&relative) {
+                log::debug!("excluded: {}", relative.display());
+                continue;
+            }
+
+            let mut file_type = entry.file_type()?;
+            if file_type.is_symlink() {
+                match args.symlinks {
+                    SymlinkPolicy::Skip => {
+                        self.skipped.push((relative, "symbolic link"));
+                        continue;
+                    }
+                    SymlinkPolicy::Error => {
+                        return Err(anyhow!("{} is a symbolic link", path.display()));
+                    }
+                    SymlinkPolicy::Follow => file_type = std::fs::metadata(&path)?.file_type(),
+                }
+            }
+
+            if file_type.is_dir() {
+                if !args.recursive {
+                    self.skipped.push((relative, "directory"));
+                    continue;
+                }
+                // a followed link back to one of its own parents
+                let canonical = path.canonicalize()?;
+                if ancestors.contains(&canonical) {
+                    self.skipped.push((relative, "directory loop"));
+                    continue;
+                }
+                self.dirs.push(relative.clone());
+                ancestors.push(canonical);
+                self.collect(&path, &relative, args, filter, ancestors)?;
+                ancestors.pop();
+            } else if file_type.is_file() {
+                if filter.included(&relative) {
+                    self.files.push(WalkFile {
+                        path,
+                        output: relative.clone(),
+                        relative,
+                    });
+                }
+            } else {
+                self.skipped.push((relative, "not a regular file"));
+            }
+        }
+        Ok(())
+    }
+
+    // foo.ext becomes foo.ext.fc when encrypting and foo.ext.fc becomes
+    // foo.ext when decrypting. In place, files that would keep their name are
+    // skipped since the output would replace the input.
+    fn name_outputs(&mut self, encrypt: bool, args: &WalkArgs) {
+        let suffix = args.suffix();
+        if suffix.is_empty() {
+            return;
+        }
+        for mut file in std::mem::take(&mut self.files) {
+            let encrypted = file
+                .relative
+                .extension()
+                .is_some_and(|extension| extension == suffix);
+            match (encrypt, encrypted) {
+                (true, true) if args.in_place => {
+                    self.skipped.push((file.relative, "already encrypted"));
+                    continue;
+                }
+                (false, false) if args.in_place => {
+                    self.skipped.push((file.relative, "no suffix to strip"));
+                    continue;
+                }
+                (true, _) => {
+                    let mut name = file.relative.clone().into_os_string();
+                    name.push(".");
+                    name.push(suffix);
+                    file.output = PathBuf::from(name);
+                }
+                (false, true) => file.output = file.relative.with_extension(""),
+                (false, false) => {}
+            }
+            self.files.push(file);
+        }
+    }
+}
+
+// Compares everything written to it with `expected`
+struct CompareWriter<R> {
+    expected: R,
+    buffer: Vec<u8>,
+}
+
+impl<R: Read> CompareWriter<R> {
+    fn new(expected: R) -> Self {
+        CompareWriter {
+            expected,
+            buffer: Vec::new(),
+        }
+    }
+
+    fn finish(mut self) -> Result<()> {
+        if self.expected.read(&mut [0u8; 1])? != 0 {
+            return Err(anyhow!("The round trip is shorter than the source"));
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Write for CompareWriter<R> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buffer.resize(data.len(), 0);
+        self.expected
+            .read_exact(&mut self.buffer)
+            .map_err(|_| std::io::Error::other("The round trip is longer than the source"))?;
+        if self.buffer != data {
+            return Err(std::io::Error::other(
+                "The round trip does not match the source",
+            ));
+        }
+        std::result::Result::Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::result::Result::Ok(())
+    }
+}
+
+// Best effort only, journaling or copy-on-write filesystems and SSDs may keep
+// the old blocks around.
+fn shred(path: &Path) -> Result<()> {
+    let zeros = [0u8; 64 * 1024];
+    let mut left = std::fs::metadata(path)?.len();
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    while left > 0 {
+        let len = left.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..len])?;
+        left -= len as u64;
+    }
+    file.sync_all()?;
+    drop(file);
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+// An encrypted output has to decrypt back to the source before the source is
+// removed. A decrypted output was authenticated on the way, unless the source
+// is a xor or AES-ECB file, which decrypts to garbage if damaged or forged.
+fn remove_source(
+    verifier: &dyn DynCipher,
+    source: &Path,
+    output: &Path,
+    encrypt: bool,
+) -> Result<()> {
+    if encrypt {
+        let mut encrypted = BufReader::new(File::open(output)?);
+        let mut compare = CompareWriter::new(BufReader::new(File::open(source)?));
+        verifier
+            .decrypt_dyn(&mut encrypted, &mut compare)
+            .and_then(|_| compare.finish())
+            .map_err(|err| anyhow!("{}, the source was kept", err))?;
+    } else {
+        let mut prefix = Vec::with_capacity(file_cipher::inspect::PREFIX_LEN);
+        File::open(source)?
+            .take(file_cipher::inspect::PREFIX_LEN as u64)
+            .read_to_end(&mut prefix)?;
+        let (version, _) = file_cipher::inspect::detect(&prefix)?;
+        if !version.is_authenticated() {
+            return Err(anyhow!(
+                "format {} is not authenticated, the source was kept",
+                u16::from(version)
+            ));
+        }
+    }
+    shred(source)
+}
+
+// Hands results to `report` in index order, whatever order they arrive in
+fn in_order<T>(results: impl IntoIterator<Item = (usize, T)>, mut report: impl FnMut(usize, T)) {
+    let mut pending = BTreeMap::new();
+    let mut next = 0;
+    for (index, result) in results {
+        pending.insert(index, result);
+        while let Some(result) = pending.remove(&next) {
+            report(next, result);
+            next += 1;
+        }
+    }
+}
+
+// `verifier` decrypts encrypted outputs for --remove-source. Progress and
+// the summary go to stderr, stdout may carry an output.
+fn processing(
+    cipher: &dyn DynCipher,
+    verifier: &dyn DynCipher,
+    input: &Path,
+    output: Option<&Path>,
+    encrypt: bool,
+    args: &WalkArgs,
+) -> anyhow::Result<()> {
+    let suffix = args.suffix();
+    if args.in_place && suffix.is_empty() {
+        return Err(anyhow!("--in-place needs a non-empty --suffix"));
+    }
+    if suffix.starts_with('.') || suffix.contains(std::path::is_separator) {
+        return Err(anyhow!(
+            "--suffix takes an extension without dots or separators, like fc"
+        ));
+    }
+    if args.remove_source && args.symlinks == SymlinkPolicy::Follow {
+        return Err(anyhow!(
+            "--remove-source cannot be used with --symlinks follow"
+        ));
+    }
+    let output = match output {
+        Some(output) => output,
+        None if is_stdio(input) => return Err(anyhow!("--in-place needs files, not stdin")),
+        None if input.is_dir() => input,
+        None => match input.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        },
+    };
+    if is_stdio(input) || is_stdio(output) {
+        if args.remove_source
+            || args.keep_name
+            || args.preserve
+            || args.trust_metadata
+            || args.suffix.is_some()
+        {
+            return Err(anyhow!(
+                "--remove-source, --keep-name, --preserve, --trust-metadata and --suffix need files, not stdio"
+            ));
+        }
+        return processing_stream(cipher, input, output, encrypt, &args.policy);
+    }
+    if encrypt && (args.keep_name || args.preserve) && !cipher.supports_metadata_dyn() {
+        return Err(anyhow!(
+            "--keep-name and --preserve need a format that stores file metadata, use aes or password"
+        ));
+    }
+    let mut walk = Walk::new(input, args)?;
+    walk.name_outputs(encrypt, args);
+
+    if !output.exists() {
+        std::fs::create_dir_all(output)?;
+        log::info!("Create output directory: {}", output.to_str().unwrap());
+    }
+    for dir in &walk.dirs {
+        std::fs::create_dir_all(output.join(dir))?;
+    }
+
+    let jobs = match args.jobs {
+        0 => std::thread::available_parallelism().map_or(1, |jobs| jobs.get()),
+        jobs => jobs,
+    };
+    let jobs = jobs.clamp(1, walk.files.len().max(1));
+    log::debug!("jobs: {}", jobs);
+
+    // Workers take files in input order, results are reported in that order
+    // too no matter which worker finishes first.
+    let title = if encrypt { "encrypt" } else { "decrypt" };
+    let mut failed = Vec::new();
+    let mut exists = 0;
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::channel();
+    std::thread::scope(|scope| {
+        for _ in 0..jobs {
+            let tx = tx.clone();
+            let next = &next;
+            let walk = &walk;
+            scope.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(file) = walk.files.get(index) else {
+                    break;
+                };
+                let _output = output.join(&file.output);
+                let mut result = processing_file(cipher, &file.path, &_output, encrypt, args);
+                if let (true, std::result::Result::Ok(Some((written, _)))) =
+                    (args.remove_source, &result)
+                {
+                    if let Err(err) = remove_source(verifier, &file.path, written, encrypt) {
+                        result = Err(err);
+                    }
+                }
+                if tx.send((index, result)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+
+        in_order(rx, |index, result| {
+            let WalkFile {
+                path,
+                relative,
+                output: output_relative,
+            } = &walk.files[index];
+            log::info!("{} input file: {}", title, path.display());
+            match result {
+                std::result::Result::Ok(Some((written, elapsed))) => {
+                    log::info!("{} output file: {}", title, written.display());
+                    log::trace!("elapsed time: {:?}", elapsed);
+                    if written == output.join(output_relative) {
+                        eprintln!("ok       {}", relative.display());
+                    } else {
+                        let restored = written.strip_prefix(output).unwrap_or(&written);
+                        eprintln!("ok       {} -> {}", relative.display(), restored.display());
+                    }
+                }
+                std::result::Result::Ok(None) => {
+                    eprintln!("skipped  {} (output exists)", relative.display());
+                    exists += 1;
+                }
+                Err(err) => {
+                    eprintln!("failed   {}: {}", relative.display(), err);
+                    failed.push(relative);
+                }
+            }
+        });
+    });
+
+    for (relative, reason) in &walk.skipped {
+        eprintln!("skipped  {} ({})", relative.display(), reason);
+    }
+    eprintln!(
+        "{} processed, {} failed, {} skipped",
+        walk.files.len() - failed.len() - exists,
+        failed.len(),
+        walk.skipped.len() + exists
+    );
+
+    if !failed.is_empty() {
+        return Err(anyhow!("{} files failed", failed.len()));
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    env_logger::Builder::new()
+        .filter_level(cli.verbose.log_level_filter())
+        .init();
+
+    match cli.command {
+        Command::Archive { command } => archive(command)?,
+        Command::Inspect { input, json } => inspect(&input, json)?,
+        Command::Decrypt {
+            input,
+            output,
+            walk,
+            xor,
+            key,
+            key_file,
+            key_passphrase_fd,
+            passphrase_fd,
+            verify_with,
+            verify_with_file,
+            signer_curve,
+        } => {
+            if xor == Some(0) {
+                return Err(anyhow!("The xor parameter cannot be zero"));
+            }
+            let aes = match (key, key_file) {
+                (Some(key), _) => Some(file_cipher::aes_ecc::AesECCCipher::new(&key)),
+                (None, Some(path)) => {
+                    let mut passphrase = KeyPassphrase::new(key_passphrase_fd);
+                    let (key, curve) = read_private_key_file(&path, &mut passphrase)?;
+                    Some(file_cipher::aes_ecc::AesECCCipher::with_curve(&key, curve))
+                }
+                (None, None) if verify_with.is_some() || verify_with_file.is_some() => {
+                    return Err(anyhow!("--verify-with needs --key or --key-file"));
+                }
+                (None, None) => None,
+            };
+            let verifier = match (verify_with, verify_with_file) {
+                (Some(public_key), _) => Some((public_key, signer_curve.into())),
+                (None, Some(path)) => Some(read_public_key_file(&path)?),
+                (None, None) => None,
+            };
+            let aes = match verifier {
+                Some((public_key, curve)) => aes.map(|aes| aes.verify_with(&public_key, curve)),
+                None => aes,
+            };
+            let cipher = AutoCipher {
+                xor: xor.map(file_cipher::xor::XorCipher::new),
+                aes,
+                passphrase_fd,
+                password: OnceLock::new(),
+            };
+            processing(&cipher, &cipher, &input, output.as_deref(), false, &walk)?;
+        }
+        Command::Password {
+            input,
+            output,
+            decrypt,
+            walk,
+            passphrase_fd,
+            scrypt_log_n,
+        } => {
+            let passphrase = read_passphrase(passphrase_fd, !decrypt)?;
+            let params = file_cipher::password::ScryptParams {
+                log_n: scrypt_log_n,
+                ..Default::default()
+            };
+            let cipher = file_cipher::password::PasswordCipher::with_params(&passphrase, params);
+            drop(passphrase);
+            processing(&cipher, &cipher, &input, output.as_deref(), !decrypt, &walk)?;
+        }
+        Command::GenerateKey {
+            curve,
+            out: Some(out),
+            protect,
+            key_passphrase_fd,
+        } => {
+            let passphrase = if protect {
+                Some(read_passphrase(key_passphrase_fd, true)?)
+            } else {
+                None
+            };
+            write_key_files(&out, curve.into(), passphrase)?;
+        }
+        Command::GenerateKey {
+            curve, out: None, ..
+        } => {
+            let key = file_cipher::curve::Curve::from(curve).make_key()?;
+            println!("private_key: {}", key.private_key.to_hex());
+            println!("public_key: {}", key.public_key.to_hex());
+            return Ok(());
+        }
+        Command::Xor {
+            input,
+            output,
+            decrypt,
+            walk,
+            xor,
+        } => {
+            if xor == 0 {
+                return Err(anyhow!("The xor parameter cannot be zero"));
+            }
+
+            if decrypt && walk.remove_source {
+                return Err(anyhow!(
+                    "--remove-source cannot be used to decrypt xor files, they are not authenticated"
+                ));
+            }
+
+            let cipher = file_cipher::xor::XorCipher::new(xor);
+            processing(&cipher, &cipher, &input, output.as_deref(), !decrypt, &walk)?;
+        }
+        Command::Aes {
+            input,
+            output,
+            decrypt,
+            walk,
+            key,
+            encrypt_keys,
+            decrypt_keys,
+            roundtrip_key_file,
+        } => {
+            let mut passphrase = KeyPassphrase::new(key.key_passphrase_fd);
+            let verifier = match &roundtrip_key_file {
+                Some(path) if walk.remove_source && !decrypt => {
+                    let (private_key, curve) = read_private_key_file(path, &mut passphrase)?;
+                    Some(file_cipher::aes_ecc::AesECCCipher::with_curve(
+                        &private_key,
+                        curve,
+                    ))
+                }
+                None if walk.remove_source && !decrypt => {
+                    return Err(anyhow!(
+                        "--remove-source needs --roundtrip-key-file to check the encrypted files"
+                    ));
+                }
+                _ => None,
+            };
+            let cipher = key.cipher(decrypt, encrypt_keys, decrypt_keys, &mut passphrase)?;
+            drop(passphrase);
+            processing(
+                &cipher,
+                verifier.as_ref().unwrap_or(&cipher),
+                &input,
+                output.as_deref(),
+                !decrypt,
+                &walk,
+            )?;
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A fresh directory for one test
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("file_cipher_cli_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // WalkArgs as the command line gives them
+    fn walk_args(args: &[&str]) -> WalkArgs {
+        let command = [
+            "file_cipher_cli",
+            "xor",
+            "-i",
+            "in",
+            "-o",
+            "out",
+            "--xor",
+            "7",
+        ];
+        let cli = Cli::try_parse_from(command.iter().chain(args)).unwrap();
+        match cli.command {
+            Command::Xor { walk, .. } => walk,
+            _ => unreachable!(),
+        }
+    }
+
+    fn relative_files(walk: &Walk) -> Vec<String> {
+        walk.files
+            .iter()
+            .map(|file| file.relative.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn skipped(walk: &Walk) -> Vec<(String, &'static str)> {
+        walk.skipped
+            .iter()
+            .map(|(path, reason)| (path.to_string_lossy().into_owned(), *reason))
+            .collect()
+    }
+
+    #[test]
+    fn cli_is_consistent() {
+        use clap::CommandFactory;
+
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn archive_takes_keys_for_its_direction() {
+        let parse = |args: &[&str]| {
+            let command = ["file_cipher_cli", "archive"];
+            Cli::try_parse_from(command.iter().chain(args)).is_ok()
+        };
+        let create = ["create", "-i", "dir", "-o", "dir.fc", "-k", "00"];
+        let list = ["list", "-i", "dir.fc", "-k", "00"];
+        assert!(parse(
+            &[&create[..], &["--recipient", "01", "--sign-with", "02"]].concat()
+        ));
+        assert!(!parse(&[&create[..], &["--verify-with", "01"]].concat()));
+        assert!(!parse(
+            &[&create[..], &["--roundtrip-key-file", "key.pem"]].concat()
+        ));
+        assert!(parse(&[&list[..], &["--verify-with", "01"]].concat()));
+        for extra in ["--recipient", "--sign-with", "--roundtrip-key-file"] {
+            assert!(!parse(&[&list[..], &[extra, "01"]].concat()));
+        }
+    }
+
+    #[test]
+    fn results_are_reported_in_input_order() {
+        let arrived = [(2, "c"), (0, "a"), (3, "d"), (1, "b"), (5, "f"), (4, "e")];
+        let mut reported = Vec::new();
+        in_order(arrived, |index, result| reported.push((index, result)));
+        assert_eq!(
+            reported,
+            [(0, "a"), (1, "b"), (2, "c"), (3, "d"), (4, "e"), (5, "f")]
+        );
+
+        // a gap holds back everything after it
+        let mut reported = Vec::new();
+        in_order([(1, "b"), (2, "c")], |index, _| reported.push(index));
+        assert!(reported.is_empty());
+    }
+
+    #[test]
+    fn stream_to_stdout_or_a_file() {
+        let root = temp_dir("stream");
+        let cipher = file_cipher::xor::XorCipher::new(7);
+        let policy = |force, no_clobber| OutputArgs { force, no_clobber };
+        let stdio = Path::new("-");
+
+        let mut stdout = Vec::new();
+        stream_to(
+            &cipher,
+            &mut &b"streamed"[..],
+            stdio,
+            true,
+            &policy(false, false),
+            &mut stdout,
+        )
+        .unwrap();
+        let mut decrypted = Vec::new();
+        stream_to(
+            &cipher,
+            &mut &stdout[..],
+            stdio,
+            false,
+            &policy(false, false),
+            &mut decrypted,
+        )
+        .unwrap();
+        assert_eq!(decrypted, b"streamed");
+
+        // -i - with -o naming a file leaves stdout alone
+        let file = root.join("out.fc");
+        let mut stdout = Vec::new();
+        stream_to(
+            &cipher,
+            &mut &b"first"[..],
+            &file,
+            true,
+            &policy(false, false),
+            &mut stdout,
+        )
+        .unwrap();
+        assert!(stdout.is_empty());
+        let first = std::fs::read(&file).unwrap();
+
+        let second = |policy: &OutputArgs| {
+            stream_to(
+                &cipher,
+                &mut &b"second"[..],
+                &file,
+                true,
+                policy,
+                &mut Vec::new(),
+            )
+        };
+        assert!(second(&policy(false, false)).is_err());
+        second(&policy(false, true)).unwrap();
+        assert_eq!(std::fs::read(&file).unwrap(), first);
+        second(&policy(true, false)).unwrap();
+        assert_ne!(std::fs::read(&file).unwrap(), first);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn stdio_refuses_file_options() {
+        let cipher = file_cipher::xor::XorCipher::new(7);
+        let options: [&[&str]; 5] = [
+            &["--keep-name"],
+            &["--preserve"],
+            &["--trust-metadata"],
+            &["--remove-source"],
+            &["--suffix", "enc"],
+        ];
+        for option in options {
+            let args = walk_args(option);
+            for (input, output) in [("-", "out"), ("in", "-")] {
+                let err = processing(
+                    &cipher,
+                    &cipher,
+                    Path::new(input),
+                    Some(Path::new(output)),
+                    true,
+                    &args,
+                )
+                .unwrap_err();
+                assert!(err.to_string().contains("not stdio"), "{}", err);
+            }
+        }
+
+        // no output directory means --in-place
+        let args = walk_args(&[]);
+        assert!(processing(&cipher, &cipher, Path::new("-"), None, true, &args).is_err());
+    }
+
+    #[test]
+    fn auto_cipher_detects_the_format() {
+        let plaintext = b"whatever it was encrypted with";
+        let encrypt = |cipher: &dyn DynCipher| {
+            let mut encrypted = Vec::new();
+            cipher
+                .encrypt_dyn(&mut &plaintext[..], &mut encrypted)
+                .unwrap();
+            encrypted
+        };
+        // the formats this tool no longer writes only need their prefix to be routed
+        let legacy = |version: u8| {
+            let mut prefix = file_cipher::format::MAGIC.to_vec();
+            prefix.extend_from_slice(&[0, version]);
+            prefix.resize(file_cipher::inspect::PREFIX_LEN, 0);
+            prefix
+        };
+        let pair = file_cipher::curve::Curve::Secp256k1.make_key().unwrap();
+        let password = file_cipher::password::PasswordCipher::with_params(
+            "passphrase",
+            file_cipher::password::ScryptParams {
+                log_n: 10,
+                ..Default::default()
+            },
+        );
+        let v1 = encrypt(&file_cipher::xor::XorCipher::new(7));
+        let v5_ecies = encrypt(&file_cipher::aes_ecc::AesECCCipher::new(
+            &pair.public_key.to_hex(),
+        ));
+        let v5_scrypt = encrypt(&password);
+        let files: [(&[u8], &str); 7] = [
+            (&v1, "--xor"),
+            (&legacy(2), "--key"),
+            (&legacy(3), "--key"),
+            (&legacy(4), "--passphrase-fd"),
+            (&v5_ecies, "--key"),
+            (&v5_scrypt, "--passphrase-fd"),
+            (b"plain text", "not a file encrypted by file_cipher"),
+        ];
+
+        // without the cipher for a format the error names the missing option,
+        // fd 1 makes reading the password fail instead of prompting
+        let none = AutoCipher {
+            xor: None,
+            aes: None,
+            passphrase_fd: Some(1),
+            password: OnceLock::new(),
+        };
+        for (encrypted, missing) in files {
+            let err = none
+                .decrypt_dyn(&mut &encrypted[..], &mut Vec::new())
+                .unwrap_err();
+            assert!(err.to_string().contains(missing), "{}", err);
+        }
+
+        let all = AutoCipher {
+            xor: Some(file_cipher::xor::XorCipher::new(7)),
+            aes: Some(file_cipher::aes_ecc::AesECCCipher::new(
+                &pair.private_key.to_hex(),
+            )),
+            passphrase_fd: None,
+            password: OnceLock::from(std::result::Result::Ok(password)),
+        };
+        for encrypted in [&v1, &v5_ecies, &v5_scrypt] {
+            let mut decrypted = Vec::new();
+            all.decrypt_dyn(&mut &encrypted[..], &mut decrypted)
+                .unwrap();
+            assert_eq!(decrypted, plaintext);
+        }
+        // the legacy prefixes reach a cipher, which then finds the header truncated
+        for version in [2, 3, 4] {
+            let err = all
+                .decrypt_dyn(&mut &legacy(version)[..], &mut Vec::new())
+                .unwrap_err();
+            assert!(!err.to_string().contains("pass the"), "{}", err);
+        }
+    }
+
+    fn dir_entries(dir: &Path) -> Vec<String> {
+        let mut entries: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        entries.sort();
+        entries
+    }
+
+    #[test]
+    fn atomic_output_commits_or_cleans_up() {
+        let root = temp_dir("atomic");
+        let target = root.join("out");
+        let policy = |force| OutputArgs {
+            force,
+            no_clobber: false,
+        };
+
+        // dropped before commit, nothing is left behind
+        {
+            let mut atomic = AtomicOutput::create(&target).unwrap();
+            atomic.writer().write_all(b"partial").unwrap();
+        }
+        assert!(dir_entries(&root).is_empty());
+
+        // the target showed up while writing, it is only replaced with --force
+        let mut atomic = AtomicOutput::create(&target).unwrap();
+        atomic.writer().write_all(b"new").unwrap();
+        std::fs::write(&target, b"old").unwrap();
+        assert!(atomic.commit(&policy(false)).is_err());
+        assert_eq!(std::fs::read(&target).unwrap(), b"old");
+        assert_eq!(dir_entries(&root), ["out"]);
+
+        let mut atomic = AtomicOutput::create(&target).unwrap();
+        atomic.writer().write_all(b"new").unwrap();
+        atomic.commit(&policy(true)).unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"new");
+        assert_eq!(dir_entries(&root), ["out"]);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn existing_outputs_need_force() {
+        let root = temp_dir("clobber");
+        let cipher = file_cipher::xor::XorCipher::new(7);
+        let input = root.join("in.txt");
+        let output = root.join("in.txt.fc");
+        std::fs::write(&input, b"plaintext").unwrap();
+        std::fs::write(&output, b"old").unwrap();
+
+        let result = processing_file(&cipher, &input, &output, true, &walk_args(&[]));
+        assert!(result.is_err());
+        assert_eq!(std::fs::read(&output).unwrap(), b"old");
+
+        let args = walk_args(&["--no-clobber"]);
+        let result = processing_file(&cipher, &input, &output, true, &args).unwrap();
+        assert!(result.is_none());
+        assert_eq!(std::fs::read(&output).unwrap(), b"old");
+
+        let args = walk_args(&["--force"]);
+        let (written, _) = processing_file(&cipher, &input, &output, true, &args)
+            .unwrap()
+            .unwrap();
+        assert_eq!(written, output);
+        let mut decrypted = Vec::new();
+        cipher
+            .decrypt(&mut File::open(&output).unwrap(), &mut decrypted)
+            .unwrap();
+        assert_eq!(decrypted, b"plaintext");
+
+        // a failed decryption leaves neither the output nor a temp file
+        let failed = root.join("failed.txt");
+        assert!(processing_file(&cipher, &input, &failed, false, &walk_args(&[])).is_err());
+        assert_eq!(dir_entries(&root), ["in.txt", "in.txt.fc"]);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn compare_writer_needs_an_exact_match() {
+        let compare = |written: &[u8]| {
+            let mut writer = CompareWriter::new(&b"source"[..]);
+            writer
+                .write_all(written)
+                .map_err(anyhow::Error::from)
+                .and_then(|_| writer.finish())
+        };
+        compare(b"source").unwrap();
+        assert!(compare(b"sour").is_err());
+        assert!(compare(b"sources").is_err());
+        assert!(compare(b"Source").is_err());
+    }
+
+    #[test]
+    fn remove_source_checks_before_deleting() {
+        let root = temp_dir("remove_source");
+        let pair = file_cipher::curve::Curve::Secp256k1.make_key().unwrap();
+        let other = file_cipher::curve::Curve::Secp256k1.make_key().unwrap();
+        let cipher = file_cipher::aes_ecc::AesECCCipher::new(&pair.public_key.to_hex());
+        let verifier = file_cipher::aes_ecc::AesECCCipher::new(&pair.private_key.to_hex());
+        let wrong = file_cipher::aes_ecc::AesECCCipher::new(&other.private_key.to_hex());
+        let args = walk_args(&[]);
+
+        let source = root.join("a.txt");
+        let encrypted = root.join("a.txt.fc");
+        std::fs::write(&source, b"keep me").unwrap();
+        processing_file(&cipher, &source, &encrypted, true, &args).unwrap();
+
+        // the round trip has to succeed and give back the source
+        assert!(remove_source(&wrong, &source, &encrypted, true).is_err());
+        assert!(source.exists());
+        let changed = root.join("b.txt");
+        let changed_encrypted = root.join("b.txt.fc");
+        std::fs::write(&changed, b"keep me too").unwrap();
+        processing_file(&cipher, &changed, &changed_encrypted, true, &args).unwrap();
+        assert!(remove_source(&verifier, &source, &changed_encrypted, true).is_err());
+        assert!(source.exists());
+        remove_source(&verifier, &source, &encrypted, true).unwrap();
+        assert!(!source.exists());
+
+        // decrypting only removes sources that were authenticated
+        remove_source(&verifier, &changed_encrypted, &changed, false).unwrap();
+        assert!(!changed_encrypted.exists());
+        let xor = file_cipher::xor::XorCipher::new(7);
+        let xor_encrypted = root.join("x.fc");
+        processing_file(&xor, &changed, &xor_encrypted, true, &args).unwrap();
+        let err = remove_source(&xor, &xor_encrypted, &root.join("x"), false).unwrap_err();
+        assert!(err.to_string().contains("not authenticated"), "{}", err);
+        assert!(xor_encrypted.exists());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn sources_are_removed_after_their_output() {
+        let root = temp_dir("remove_after_commit");
+        let input = root.join("in");
+        let output = root.join("out");
+        std::fs::create_dir_all(&input).unwrap();
+        std::fs::create_dir_all(&output).unwrap();
+        std::fs::write(input.join("a.txt"), b"a").unwrap();
+        std::fs::write(input.join("b.txt"), b"b").unwrap();
+        // b's output cannot be committed without --force
+        std::fs::write(output.join("b.txt.fc"), b"old").unwrap();
+
+        let cipher = file_cipher::password::PasswordCipher::with_params(
+            "passphrase",
+            file_cipher::password::ScryptParams {
+                log_n: 10,
+                ..Default::default()
+            },
+        );
+        let args = walk_args(&["--remove-source"]);
+        assert!(processing(&cipher, &cipher, &input, Some(&output), true, &args).is_err());
+        assert_eq!(dir_entries(&input), ["b.txt"]);
+        assert_eq!(dir_entries(&output), ["a.txt.fc", "b.txt.fc"]);
+        assert_eq!(std::fs::read(output.join("b.txt.fc")).unwrap(), b"old");
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn metadata_needs_a_format_that_stores_it() {
+        let root = temp_dir("metadata_format");
+        let input = root.join("in");
+        let output = root.join("out");
+        std::fs::create_dir_all(&input).unwrap();
+        std::fs::write(input.join("a.txt"), b"a").unwrap();
+
+        // xor cannot store it, refused before anything is written
+        let xor = file_cipher::xor::XorCipher::new(7);
+        for flag in ["--keep-name", "--preserve"] {
+            let args = walk_args(&[flag]);
+            assert!(processing(&xor, &xor, &input, Some(&output), true, &args).is_err());
+            assert!(!output.exists());
+        }
+
+        let cipher = file_cipher::password::PasswordCipher::with_params(
+            "passphrase",
+            file_cipher::password::ScryptParams {
+                log_n: 10,
+                ..Default::default()
+            },
+        );
+        let mtime = std::time::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        File::options()
+            .write(true)
+            .open(input.join("a.txt"))
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        let args = walk_args(&["--keep-name", "--preserve"]);
+        processing(&cipher, &cipher, &input, Some(&output), true, &args).unwrap();
+        std::fs::rename(output.join("a.txt.fc"), output.join("renamed.fc")).unwrap();
+
+        let restored = root.join("restored");
+        processing(&cipher, &cipher, &output, Some(&restored), false, &args).unwrap();
+        assert_eq!(dir_entries(&restored), ["a.txt"]);
+        let file = std::fs::metadata(restored.join("a.txt")).unwrap();
+        assert_eq!(file.modified().unwrap(), mtime);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn stored_names_stay_in_the_output_directory() {
+        let output = Path::new("out/file.fc");
+        assert_eq!(
+            restored_output(output, "notes.txt").unwrap(),
+            Path::new("out/notes.txt")
+        );
+        for name in ["", ".", "..", "a/b", "../b", "/etc/passwd"] {
+            assert!(restored_output(output, name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn special_mode_bits_need_trust() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = temp_dir("apply_metadata");
+        let path = root.join("file");
+        std::fs::write(&path, b"data").unwrap();
+        let mtime = std::time::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let metadata = Metadata {
+            mode: Some(0o104751),
+            mtime: Some(mtime),
+            ..Default::default()
+        };
+        let mode = || std::fs::metadata(&path).unwrap().permissions().mode() & 0o7777;
+
+        apply_metadata(&path, &metadata, false).unwrap();
+        assert_eq!(mode(), 0o751);
+        assert_eq!(std::fs::metadata(&path).unwrap().modified().unwrap(), mtime);
+
+        apply_metadata(&path, &metadata, true).unwrap();
+        assert_eq!(mode(), 0o4751);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn walk_filters_relative_paths() {
+        let root = temp_dir("walk_filters");
+        std::fs::create_dir_all(root.join("sub/skip")).unwrap();
+        for file in [
+            "a.txt",
+            "b.log",
+            ".DS_Store",
+            "sub/c.txt",
+            "sub/d.log",
+            "sub/skip/e.txt",
+        ] {
+            std::fs::write(root.join(file), file).unwrap();
+        }
+
+        let walk = Walk::new(&root, &walk_args(&["-r"])).unwrap();
+        assert_eq!(
+            relative_files(&walk),
+            ["a.txt", "b.log", "sub/c.txt", "sub/d.log", "sub/skip/e.txt"]
+        );
+        assert_eq!(walk.dirs, [PathBuf::from("sub"), PathBuf::from("sub/skip")]);
+
+        // a pattern matches the file name or the whole relative path
+        let args = walk_args(&["-r", "--include", "*.txt", "--exclude", "sub/skip"]);
+        let walk = Walk::new(&root, &args).unwrap();
+        assert_eq!(relative_files(&walk), ["a.txt", "sub/c.txt"]);
+        assert_eq!(walk.dirs, [PathBuf::from("sub")]);
+
+        let args = walk_args(&["-r", "--include", "sub/*.log", "--no-default-excludes"]);
+        assert_eq!(
+            relative_files(&Walk::new(&root, &args).unwrap()),
+            ["sub/d.log"]
+        );
+        let args = walk_args(&["-r", "--no-default-excludes", "--exclude", "*.log"]);
+        assert_eq!(
+            relative_files(&Walk::new(&root, &args).unwrap()),
+            [".DS_Store", "a.txt", "sub/c.txt", "sub/skip/e.txt"]
+        );
+
+        // without -r directories are reported, not entered
+        let walk = Walk::new(&root, &walk_args(&[])).unwrap();
+        assert_eq!(relative_files(&walk), ["a.txt", "b.log"]);
+        assert_eq!(skipped(&walk), [("sub".to_owned(), "directory")]);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn walk_symlink_policy() {
+        use std::os::unix::fs::symlink;
+
+        let root = temp_dir("walk_symlinks");
+        std::fs::create_dir_all(root.join("real")).unwrap();
+        std::fs::write(root.join("real/f.txt"), b"f").unwrap();
+        symlink("real", root.join("link")).unwrap();
+        symlink("real/f.txt", root.join("flink")).unwrap();
+        // back to the input directory itself
+        symlink("..", root.join("real/back")).unwrap();
+
+        let walk = Walk::new(&root, &walk_args(&["-r"])).unwrap();
+        assert_eq!(relative_files(&walk), ["real/f.txt"]);
+        assert_eq!(
+            skipped(&walk),
+            [
+                ("flink".to_owned(), "symbolic link"),
+                ("link".to_owned(), "symbolic link"),
+                ("real/back".to_owned(), "symbolic link"),
+            ]
+        );
+
+        let walk = Walk::new(&root, &walk_args(&["-r", "--symlinks", "follow"])).unwrap();
+        assert_eq!(relative_files(&walk), ["flink", "link/f.txt", "real/f.txt"]);
+        assert_eq!(
+            skipped(&walk),
+            [
+                ("link/back".to_owned(), "directory loop"),
+                ("real/back".to_owned(), "directory loop"),
+            ]
+        );
+
+        assert!(Walk::new(&root, &walk_args(&["-r", "--symlinks", "error"])).is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn passphrase_fd_is_borrowed() {
+        use std::os::fd::AsRawFd;
+
+        for fd in 0..=2 {
+            assert!(read_passphrase_fd(fd).is_err());
+        }
+
+        let (mut reader, mut writer) = std::io::pipe().unwrap();
+        writer.write_all(b"secret\nignored").unwrap();
+        drop(writer);
+        let mut passphrase = KeyPassphrase::new(Some(reader.as_raw_fd()));
+        assert_eq!(passphrase.get().unwrap().as_str(), "secret");
+        // read once and shared, the descriptor stays open
+        assert_eq!(passphrase.get().unwrap().as_str(), "secret");
+        assert_eq!(reader.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+}
@@ -0,0 +1,249 @@
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// The metadata record sits at the start of the encrypted payload, so it is
+// confidential and authenticated like the file content. It is a u32 length
+// followed by tag (u8), length (u32), value entries. Unknown tags are skipped
+// so newer writers can add entries.
+const TAG_NAME: u8 = 0x01;
+const TAG_MODE: u8 = 0x02;
+const TAG_MTIME: u8 = 0x03;
+const TAG_ATIME: u8 = 0x04;
+// one entry per attribute: u16 name length, name, value
+const TAG_XATTR: u8 = 0x05;
+
+const MAX_METADATA_LEN: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    // original file name, without any directory
+    pub name: Option<String>,
+    // Unix permission bits
+    pub mode: Option<u32>,
+    pub mtime: Option<SystemTime>,
+    pub atime: Option<SystemTime>,
+    // extended attributes as name and value
+    pub xattrs: Vec<(String, Vec<u8>)>,
+}
+
+impl Metadata {
+    pub fn with_name(name: &str) -> Self {
+        Metadata {
+            name: Some(name.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        if let Some(name) = &self.name {
+            push_entry(&mut body, TAG_NAME, name.as_bytes());
+        }
+        if let Some(mode) = self.mode {
+            push_entry(&mut body, TAG_MODE, &mode.to_be_bytes());
+        }
+        if let Some(mtime) = self.mtime {
+            push_entry(&mut body, TAG_MTIME, &time_to_bytes(mtime));
+        }
+        if let Some(atime) = self.atime {
+            push_entry(&mut body, TAG_ATIME, &time_to_bytes(atime));
+        }
+        for (name, value) in &self.xattrs {
+            let name_len = u16::try_from(name.len())
+                .map_err(|_| anyhow::anyhow!("The extended attribute name is too long"))?;
+            let mut entry = name_len.to_be_bytes().to_vec();
+            entry.extend_from_slice(name.as_bytes());
+            entry.extend_from_slice(value);
+            push_entry(&mut body, TAG_XATTR, &entry);
+        }
+        if body.len() > MAX_METADATA_LEN {
+            return Err(anyhow::anyhow!("The file metadata is too large"));
+        }
+        let mut bytes = (body.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(&body);
+        Ok(bytes)
+    }
+
+    fn from_body(mut body: &[u8]) -> anyhow::Result<Self> {
+        let mut metadata = Metadata::default();
+        while !body.is_empty() {
+            if body.len() < 5 {
+                return Err(anyhow::anyhow!("The file metadata is truncated"));
+            }
+            let tag = body[0];
+            let len = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+            let value = body
+                .get(5..5 + len)
+                .ok_or_else(|| anyhow::anyhow!("The file metadata is truncated"))?;
+            match tag {
+                TAG_NAME => metadata.name = Some(String::from_utf8(value.to_vec())?),
+                TAG_MODE => {
+                    let mode = value
+                        .try_into()
+                        .map_err(|_| anyhow::anyhow!("Invalid file mode in the metadata"))?;
+                    metadata.mode = Some(u32::from_be_bytes(mode));
+                }
+                TAG_MTIME => metadata.mtime = Some(time_from_bytes(value)?),
+                TAG_ATIME => metadata.atime = Some(time_from_bytes(value)?),
+                TAG_XATTR => metadata.xattrs.push(xattr_from_bytes(value)?),
+                _ => log::debug!("unknown metadata tag: {}", tag),
+            }
+            body = &body[5 + len..];
+        }
+        Ok(metadata)
+    }
+}
+
+// seconds since the epoch as i64, then nanoseconds as u32
+fn time_to_bytes(time: SystemTime) -> [u8; 12] {
+    let (secs, nanos) = match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => (since.as_secs() as i64, since.subsec_nanos()),
+        Err(err) => {
+            let before = err.duration();
+            match before.subsec_nanos() {
+                0 => (-(before.as_secs() as i64), 0),
+                nanos => (-(before.as_secs() as i64) - 1, 1_000_000_000 - nanos),
+            }
+        }
+    };
+    let mut bytes = [0u8; 12];
+    bytes[..8].copy_from_slice(&secs.to_be_bytes());
+    bytes[8..].copy_from_slice(&nanos.to_be_bytes());
+    bytes
+}
+
+fn time_from_bytes(bytes: &[u8]) -> anyhow::Result<SystemTime> {
+    let invalid = || anyhow::anyhow!("Invalid timestamp in the metadata");
+    if bytes.len() != 12 {
+        return Err(invalid());
+    }
+    let secs = i64::from_be_bytes(bytes[..8].try_into()?);
+    let nanos = u32::from_be_bytes(bytes[8..].try_into()?);
+    if nanos >= 1_000_000_000 {
+        return Err(invalid());
+    }
+    let time = if secs >= 0 {
+        UNIX_EPOCH.checked_add(Duration::new(secs as u64, 0))
+    } else {
+        UNIX_EPOCH.checked_sub(Duration::new(secs.unsigned_abs(), 0))
+    };
+    time.and_then(|time| time.checked_add(Duration::new(0, nanos)))
+        .ok_or_else(invalid)
+}
+
+fn xattr_from_bytes(bytes: &[u8]) -> anyhow::Result<(String, Vec<u8>)> {
+    let invalid = || anyhow::anyhow!("Invalid extended attribute in the metadata");
+    let name_len = u16::from_be_bytes(bytes.get(..2).ok_or_else(invalid)?.try_into()?) as usize;
+    let name = bytes.get(2..2 + name_len).ok_or_else(invalid)?;
+    Ok((
+        String::from_utf8(name.to_vec())?,
+        bytes[2 + name_len..].to_vec(),
+    ))
+}
+
+fn push_entry(body: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    body.push(tag);
+    body.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    body.extend_from_slice(value);
+}
+
+// Takes the metadata record off the front of the decrypted payload and passes
+// the rest through. Without a record it is a plain pass-through.
+pub(crate) struct MetadataSplitter<W> {
+    inner: W,
+    record: Option<Vec<u8>>,
+    metadata: Option<Metadata>,
+}
+
+impl<W: Write> MetadataSplitter<W> {
+    pub(crate) fn new(inner: W, has_metadata: bool) -> Self {
+        MetadataSplitter {
+            inner,
+            record: has_metadata.then(Vec::new),
+            metadata: None,
+        }
+    }
+
+    // Length of the whole record once its prefix is known
+    fn record_len(record: &[u8]) -> Option<usize> {
+        let prefix = record.get(..4)?;
+        Some(4 + u32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as usize)
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub(crate) fn finish(self) -> anyhow::Result<Option<Metadata>> {
+        if self.record.is_some() {
+            return Err(anyhow::anyhow!("The file metadata is truncated"));
+        }
+        Ok(self.metadata)
+    }
+}
+
+impl<W: Write> Write for MetadataSplitter<W> {
+    fn write(&mut self, mut data: &[u8]) -> std::io::Result<usize> {
+        let written = data.len();
+        if let Some(record) = &mut self.record {
+            while !data.is_empty() {
+                let wanted = match Self::record_len(record) {
+                    Some(len) if len - 4 > MAX_METADATA_LEN => {
+                        return Err(std::io::Error::other("The file metadata is too large"));
+                    }
+                    Some(len) => len,
+                    None => 4,
+                };
+                let take = (wanted - record.len()).min(data.len());
+                record.extend_from_slice(&data[..take]);
+                data = &data[take..];
+                if Self::record_len(record) == Some(record.len()) {
+                    let metadata =
+                        Metadata::from_body(&record[4..]).map_err(std::io::Error::other)?;
+                    self.metadata = Some(metadata);
+                    self.record = None;
+                    break;
+                }
+            }
+        }
+        self.inner.write_all(data)?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_record_written_in_pieces() {
+        let metadata = Metadata::with_name("report.pdf");
+        let mut payload = metadata.to_bytes().unwrap();
+        payload.extend_from_slice(b"content");
+
+        let mut out = Vec::new();
+        let mut splitter = MetadataSplitter::new(&mut out, true);
+        for byte in &payload {
+            splitter.write_all(&[*byte]).unwrap();
+        }
+        assert_eq!(splitter.finish().unwrap(), Some(metadata));
+        assert_eq!(out, b"content");
+    }
+
+    #[test]
+    fn file_attributes_roundtrip() {
+        let metadata = Metadata {
+            name: None,
+            mode: Some(0o755),
+            mtime: Some(UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789)),
+            atime: Some(UNIX_EPOCH - Duration::new(10, 500)),
+            xattrs: vec![("user.origin".to_owned(), b"build cache".to_vec())],
+        };
+        let bytes = metadata.to_bytes().unwrap();
+        assert_eq!(Metadata::from_body(&bytes[4..]).unwrap(), metadata);
+    }
+}
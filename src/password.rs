@@ -0,0 +1,351 @@
+use byte_struct::*;
+
+use std::io::{Read, Write};
+
+use crate::cipher::Cipher;
+use crate::format::{self, Algorithm, ExtensibleHeader, Extension};
+use crate::header;
+use crate::kdf;
+use crate::metadata::{Metadata, MetadataSplitter};
+use crate::stream::{self, StreamDecryptor, StreamEncryptor};
+use crate::utils;
+use crate::version::Version;
+
+const V4_KEY_INFO: &[u8] = b"file_cipher v4 aes-256-gcm key";
+const V4_NONCE_INFO: &[u8] = b"file_cipher v4 aes-256-gcm nonce";
+
+// scrypt needs 128 * r * N bytes, decryption refuses headers asking for more
+const MAX_SCRYPT_MEMORY: u64 = 1 << 30;
+
+// scrypt cost parameters, N = 2^log_n
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScryptParams {
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+}
+
+impl Default for ScryptParams {
+    // 128 MiB, roughly half a second on a laptop
+    fn default() -> Self {
+        ScryptParams {
+            log_n: 17,
+            r: 8,
+            p: 1,
+        }
+    }
+}
+
+impl ScryptParams {
+    fn check(&self) -> anyhow::Result<()> {
+        if !(1..=20).contains(&self.log_n)
+            || !(1..=32).contains(&self.r)
+            || !(1..=16).contains(&self.p)
+            || self.memory() > MAX_SCRYPT_MEMORY
+        {
+            return Err(anyhow::anyhow!(
+                "Unsupported scrypt parameters: log_n {}, r {}, p {}",
+                self.log_n,
+                self.r,
+                self.p
+            ));
+        }
+        Ok(())
+    }
+
+    fn memory(&self) -> u64 {
+        128 * self.r as u64 * (1u64 << self.log_n)
+    }
+
+    // OpenSSL also counts the 128 * r * p bytes of B against maxmem
+    fn max_memory(&self) -> u64 {
+        128 * self.r as u64 * ((1u64 << self.log_n) + self.p as u64 + 2)
+    }
+}
+
+fn scrypt_key(password: &[u8], params: ScryptParams, salt: &[u8]) -> anyhow::Result<[u8; 32]> {
+    params.check()?;
+    let mut master_key = [0u8; 32];
+    openssl::pkcs5::scrypt(
+        password,
+        salt,
+        1u64 << params.log_n,
+        params.r as u64,
+        params.p as u64,
+        params.max_memory(),
+        &mut master_key,
+    )?;
+    Ok(master_key)
+}
+
+fn derive_v4_keys(
+    password: &[u8],
+    header: &header::PasswordHeader,
+) -> anyhow::Result<([u8; 32], [u8; header::AES_GCM_NONCE_LEN])> {
+    let params = ScryptParams {
+        log_n: header.log_n(),
+        r: header.r(),
+        p: header.p(),
+    };
+    let master_key = scrypt_key(password, params, header.salt_bytes())?;
+
+    let info = header.kdf_info();
+    let mut key = [0u8; 32];
+    let mut nonce = [0u8; header::AES_GCM_NONCE_LEN];
+    kdf::hkdf_sha256(
+        &master_key,
+        header.salt_bytes(),
+        &[&info, V4_KEY_INFO],
+        &mut key,
+    )?;
+    kdf::hkdf_sha256(
+        &master_key,
+        header.salt_bytes(),
+        &[&info, V4_NONCE_INFO],
+        &mut nonce,
+    )?;
+    Ok((key, nonce))
+}
+
+pub struct PasswordCipher {
+    password: String,
+    params: ScryptParams,
+}
+
+impl PasswordCipher {
+    pub fn new(password: &str) -> Self {
+        PasswordCipher {
+            password: password.to_string(),
+            params: ScryptParams::default(),
+        }
+    }
+
+    // Cost parameters used when encrypting, decryption reads them from the header
+    pub fn with_params(password: &str, params: ScryptParams) -> Self {
+        PasswordCipher {
+            password: password.to_string(),
+            params,
+        }
+    }
+}
+
+impl PasswordCipher {
+    // The metadata record, if any, goes through the stream ahead of the data
+    fn encrypt_v5<R, W>(
+        &self,
+        metadata: Option<&Metadata>,
+        src: &mut R,
+        dst: &mut W,
+    ) -> anyhow::Result<()>
+    where
+        R: Read,
+        W: Write,
+    {
+        if self.password.is_empty() {
+            return Err(anyhow::anyhow!("The passphrase cannot be empty"));
+        }
+        self.params.check()?;
+
+        let salt = utils::generate_random_bytes::<{ header::HKDF_SALT_LEN }>();
+        let mut header = ExtensibleHeader::new(Algorithm::Scrypt, stream::DEFAULT_CHUNK_SIZE, salt);
+        header.extensions.push(Extension::Scrypt(self.params));
+        if metadata.is_some() {
+            header.extensions.push(Extension::Metadata);
+        }
+        let master_key = scrypt_key(self.password.as_bytes(), self.params, &salt)?;
+        let (aes_key, nonce) = header.payload_keys(&master_key)?;
+        log::trace!("scrypt: {:?}", self.params);
+        log::trace!("salt: {}", utils::encode_hex(&salt));
+
+        let header_bytes = header.to_bytes()?;
+        dst.write_all(&header_bytes)?;
+
+        let record = match metadata {
+            Some(metadata) => metadata.to_bytes()?,
+            None => Vec::new(),
+        };
+        let encryptor = StreamEncryptor::new(aes_key, nonce, stream::DEFAULT_CHUNK_SIZE)
+            .with_aad(&header_bytes);
+        stream::pump(encryptor, &mut (&record[..]).chain(src), dst)
+    }
+
+    fn decrypt_any<R, W>(&self, src: &mut R, dst: &mut W) -> anyhow::Result<Option<Metadata>>
+    where
+        R: Read,
+        W: Write,
+    {
+        let mut prefix = [0u8; header::FORMAT_PREFIX_LEN];
+        src.read_exact(&mut prefix)?;
+        let (decryptor, has_metadata) = match header::read_format(&prefix)? {
+            Version::V4 => {
+                let mut header_bytes = [0u8; header::PasswordHeader::BYTE_LEN];
+                header_bytes[..header::FORMAT_PREFIX_LEN].copy_from_slice(&prefix);
+                src.read_exact(&mut header_bytes[header::FORMAT_PREFIX_LEN..])?;
+                let header = header::PasswordHeader::try_from(&header_bytes[..])?;
+
+                let chunk_size = header.chunk_size();
+                stream::check_chunk_size(chunk_size)?;
+                let (aes_key, nonce) = derive_v4_keys(self.password.as_bytes(), &header)?;
+                log::trace!("salt: {}", utils::encode_hex(header.salt_bytes()));
+                log::trace!("chunk_size: {}", chunk_size);
+                (StreamDecryptor::new(aes_key, nonce, chunk_size), false)
+            }
+            Version::V5 => {
+                let (header, header_bytes) = format::read_extensible_header(&prefix, src)?;
+                let params = match (header.algorithm, header.scrypt()) {
+                    (Algorithm::Scrypt, Some(params)) => params,
+                    _ => {
+                        return Err(anyhow::anyhow!(
+                            "The input file was encrypted for a public key"
+                        ))
+                    }
+                };
+                stream::check_chunk_size(header.chunk_size)?;
+                let master_key = scrypt_key(self.password.as_bytes(), params, &header.salt)?;
+                let (aes_key, nonce) = header.payload_keys(&master_key)?;
+                log::trace!("salt: {}", utils::encode_hex(&header.salt));
+                log::trace!("chunk_size: {}", header.chunk_size);
+                (
+                    StreamDecryptor::new(aes_key, nonce, header.chunk_size).with_aad(&header_bytes),
+                    header.metadata(),
+                )
+            }
+            _ => return Err(anyhow::anyhow!("Only v4 and v5 are supported")),
+        };
+
+        // a wrong passphrase fails authentication of the first chunk
+        let mut splitter = MetadataSplitter::new(dst, has_metadata);
+        stream::pump(decryptor, src, &mut splitter)?;
+        splitter.finish()
+    }
+}
+
+impl Cipher for PasswordCipher {
+    fn encrypt<R, W>(&self, src: &mut R, dst: &mut W) -> anyhow::Result<()>
+    where
+        R: Read,
+        W: Write,
+    {
+        self.encrypt_v5(None, src, dst)
+    }
+
+    fn decrypt<R, W>(&self, src: &mut R, dst: &mut W) -> anyhow::Result<()>
+    where
+        R: Read,
+        W: Write,
+    {
+        self.decrypt_any(src, dst).map(|_| ())
+    }
+
+    fn supports_metadata(&self) -> bool {
+        true
+    }
+
+    fn encrypt_with_metadata<R, W>(
+        &self,
+        metadata: &Metadata,
+        src: &mut R,
+        dst: &mut W,
+    ) -> anyhow::Result<()>
+    where
+        R: Read,
+        W: Write,
+    {
+        self.encrypt_v5(Some(metadata), src, dst)
+    }
+
+    fn decrypt_with_metadata<R, W>(
+        &self,
+        src: &mut R,
+        dst: &mut W,
+    ) -> anyhow::Result<Option<Metadata>>
+    where
+        R: Read,
+        W: Write,
+    {
+        self.decrypt_any(src, dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAST: ScryptParams = ScryptParams {
+        log_n: 10,
+        r: 8,
+        p: 1,
+    };
+
+    #[test]
+    fn encrypt_decrypt_v5() {
+        let plaintext = b"correct horse battery staple".repeat(5000);
+
+        let mut encrypted = Vec::new();
+        PasswordCipher::with_params("passphrase", FAST)
+            .encrypt(&mut &plaintext[..], &mut encrypted)
+            .unwrap();
+        assert_eq!(header::read_format(&encrypted).unwrap(), Version::V5);
+
+        let mut decrypted = Vec::new();
+        PasswordCipher::new("passphrase")
+            .decrypt(&mut &encrypted[..], &mut decrypted)
+            .unwrap();
+        assert_eq!(decrypted, plaintext);
+
+        let mut decrypted = Vec::new();
+        let result = PasswordCipher::new("wrong").decrypt(&mut &encrypted[..], &mut decrypted);
+        assert!(result.is_err());
+        assert!(decrypted.is_empty());
+    }
+
+    #[test]
+    fn metadata_roundtrip() {
+        let plaintext = b"named and dated".repeat(5000);
+        let metadata = Metadata::with_name("notes.txt");
+
+        let mut encrypted = Vec::new();
+        PasswordCipher::with_params("passphrase", FAST)
+            .encrypt_with_metadata(&metadata, &mut &plaintext[..], &mut encrypted)
+            .unwrap();
+
+        let mut decrypted = Vec::new();
+        let restored = PasswordCipher::new("passphrase")
+            .decrypt_with_metadata(&mut &encrypted[..], &mut decrypted)
+            .unwrap();
+        assert_eq!(restored, Some(metadata));
+        assert_eq!(decrypted, plaintext);
+
+        // plain decrypt drops the record
+        let mut decrypted = Vec::new();
+        PasswordCipher::new("passphrase")
+            .decrypt(&mut &encrypted[..], &mut decrypted)
+            .unwrap();
+        assert_eq!(decrypted, plaintext);
+    }
+
+    #[test]
+    fn decrypt_legacy_v4() {
+        let plaintext = b"archived with file_cipher v4".repeat(5000);
+        let salt = utils::generate_random_bytes::<{ header::HKDF_SALT_LEN }>();
+        let header = header::PasswordHeader::new(
+            FAST.log_n,
+            FAST.r,
+            FAST.p,
+            stream::DEFAULT_CHUNK_SIZE,
+            &salt,
+        );
+        let mut encrypted = vec![0u8; header::PasswordHeader::BYTE_LEN];
+        header.write_bytes(&mut encrypted);
+        let (aes_key, nonce) = derive_v4_keys(b"passphrase", &header).unwrap();
+        let mut encryptor = StreamEncryptor::new(aes_key, nonce, stream::DEFAULT_CHUNK_SIZE);
+        encryptor.update(&plaintext, &mut encrypted).unwrap();
+        encryptor.finalize(&mut encrypted).unwrap();
+
+        let mut decrypted = Vec::new();
+        PasswordCipher::new("passphrase")
+            .decrypt(&mut &encrypted[..], &mut decrypted)
+            .unwrap();
+        assert_eq!(decrypted, plaintext);
+    }
+}
@@ -0,0 +1,335 @@
+use std::io::{Read, Write};
+
+use openssl::symm::{self, Cipher as AesCipher};
+
+use crate::header::{AES_GCM_NONCE_LEN, AES_GCM_TAG_LEN};
+
+pub(crate) const DEFAULT_CHUNK_SIZE: u32 = 64 * 1024;
+pub(crate) const MAX_CHUNK_SIZE: u32 = 16 * 1024 * 1024;
+
+// STREAM construction: the payload is cut into chunk_size pieces, each sealed
+// with AES-256-GCM under nonce_base ^ (counter || last_flag).
+// Every chunk except the last one holds exactly chunk_size bytes of plaintext,
+// so truncation at a chunk boundary and reordering both fail authentication.
+fn chunk_nonce(
+    base: &[u8; AES_GCM_NONCE_LEN],
+    counter: u64,
+    last: bool,
+) -> [u8; AES_GCM_NONCE_LEN] {
+    let mut nonce = *base;
+    for (n, c) in nonce[3..11].iter_mut().zip(counter.to_be_bytes().iter()) {
+        *n ^= c;
+    }
+    if last {
+        nonce[11] ^= 0x01;
+    }
+    nonce
+}
+
+pub(crate) fn check_chunk_size(chunk_size: u32) -> anyhow::Result<()> {
+    if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+        return Err(anyhow::anyhow!("Unsupported chunk size: {}", chunk_size));
+    }
+    Ok(())
+}
+
+pub(crate) struct StreamEncryptor {
+    key: [u8; 32],
+    nonce: [u8; AES_GCM_NONCE_LEN],
+    counter: u64,
+    chunk_size: usize,
+    buffer: Vec<u8>,
+    // associated data of every chunk, the header from format 5 on
+    aad: Vec<u8>,
+}
+
+impl StreamEncryptor {
+    pub(crate) fn new(key: [u8; 32], nonce: [u8; AES_GCM_NONCE_LEN], chunk_size: u32) -> Self {
+        StreamEncryptor {
+            key,
+            nonce,
+            counter: 0,
+            chunk_size: chunk_size as usize,
+            buffer: Vec::with_capacity(chunk_size as usize),
+            aad: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with_aad(mut self, aad: &[u8]) -> Self {
+        self.aad = aad.to_vec();
+        self
+    }
+
+    fn seal(&mut self, plaintext: &[u8], last: bool, out: &mut Vec<u8>) -> anyhow::Result<()> {
+        let nonce = chunk_nonce(&self.nonce, self.counter, last);
+        let mut tag = [0u8; AES_GCM_TAG_LEN];
+        let ciphertext = symm::encrypt_aead(
+            AesCipher::aes_256_gcm(),
+            &self.key,
+            Some(&nonce),
+            &self.aad,
+            plaintext,
+            &mut tag,
+        )?;
+        out.extend_from_slice(&ciphertext);
+        out.extend_from_slice(&tag);
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| anyhow::anyhow!("Too many chunks"))?;
+        Ok(())
+    }
+
+    // A full chunk is only sealed once more data follows it, the final chunk
+    // has to be sealed with the last flag set in `finalize`.
+    pub(crate) fn update(&mut self, mut data: &[u8], out: &mut Vec<u8>) -> anyhow::Result<()> {
+        while !data.is_empty() {
+            if self.buffer.len() == self.chunk_size {
+                let chunk = std::mem::take(&mut self.buffer);
+                self.seal(&chunk, false, out)?;
+                self.buffer = chunk;
+                self.buffer.clear();
+            }
+            let take = std::cmp::min(self.chunk_size - self.buffer.len(), data.len());
+            self.buffer.extend_from_slice(&data[..take]);
+            data = &data[take..];
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finalize(mut self, out: &mut Vec<u8>) -> anyhow::Result<()> {
+        let chunk = std::mem::take(&mut self.buffer);
+        self.seal(&chunk, true, out)
+    }
+}
+
+pub(crate) struct StreamDecryptor {
+    key: [u8; 32],
+    nonce: [u8; AES_GCM_NONCE_LEN],
+    counter: u64,
+    chunk_size: usize,
+    buffer: Vec<u8>,
+    // associated data of every chunk, the header from format 5 on
+    aad: Vec<u8>,
+}
+
+impl StreamDecryptor {
+    pub(crate) fn new(key: [u8; 32], nonce: [u8; AES_GCM_NONCE_LEN], chunk_size: u32) -> Self {
+        StreamDecryptor {
+            key,
+            nonce,
+            counter: 0,
+            chunk_size: chunk_size as usize,
+            buffer: Vec::with_capacity(chunk_size as usize + AES_GCM_TAG_LEN),
+            aad: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with_aad(mut self, aad: &[u8]) -> Self {
+        self.aad = aad.to_vec();
+        self
+    }
+
+    fn open(&mut self, len: usize, last: bool, out: &mut Vec<u8>) -> anyhow::Result<()> {
+        let nonce = chunk_nonce(&self.nonce, self.counter, last);
+        let (ciphertext, tag) = self.buffer[..len].split_at(len - AES_GCM_TAG_LEN);
+        let plaintext = symm::decrypt_aead(
+            AesCipher::aes_256_gcm(),
+            &self.key,
+            Some(&nonce),
+            &self.aad,
+            ciphertext,
+            tag,
+        )
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "Authentication failed at chunk {}, the input file has been tampered with, truncated or the key is wrong",
+                self.counter
+            )
+        })?;
+        out.extend_from_slice(&plaintext);
+        self.buffer.drain(..len);
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| anyhow::anyhow!("Too many chunks"))?;
+        Ok(())
+    }
+
+    // Only chunks that are known not to be the last one are opened here, the
+    // remaining bytes are checked as the final chunk in `finalize`.
+    pub(crate) fn update(&mut self, data: &[u8], out: &mut Vec<u8>) -> anyhow::Result<()> {
+        self.buffer.extend_from_slice(data);
+        let encrypted_chunk_len = self.chunk_size + AES_GCM_TAG_LEN;
+        while self.buffer.len() > encrypted_chunk_len {
+            self.open(encrypted_chunk_len, false, out)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finalize(mut self, out: &mut Vec<u8>) -> anyhow::Result<()> {
+        let len = self.buffer.len();
+        if len < AES_GCM_TAG_LEN || (len == AES_GCM_TAG_LEN && self.counter != 0) {
+            return Err(anyhow::anyhow!("The input file is truncated"));
+        }
+        self.open(len, true, out)
+    }
+}
+
+// Read size of every loop over an input
+pub(crate) const BUFFER_SIZE: usize = 64 * 1024;
+
+// Anything fed in pieces that produces output as it goes: the STREAM
+// encryptor and decryptor and the container level types built on them
+pub(crate) trait Transform {
+    type Output;
+
+    fn update_into(&mut self, data: &[u8], out: &mut Vec<u8>) -> anyhow::Result<()>;
+
+    fn finalize_into(self, out: &mut Vec<u8>) -> anyhow::Result<Self::Output>;
+}
+
+impl Transform for StreamEncryptor {
+    type Output = ();
+
+    fn update_into(&mut self, data: &[u8], out: &mut Vec<u8>) -> anyhow::Result<()> {
+        self.update(data, out)
+    }
+
+    fn finalize_into(self, out: &mut Vec<u8>) -> anyhow::Result<()> {
+        self.finalize(out)
+    }
+}
+
+impl Transform for StreamDecryptor {
+    type Output = ();
+
+    fn update_into(&mut self, data: &[u8], out: &mut Vec<u8>) -> anyhow::Result<()> {
+        self.update(data, out)
+    }
+
+    fn finalize_into(self, out: &mut Vec<u8>) -> anyhow::Result<()> {
+        self.finalize(out)
+    }
+}
+
+// Runs everything left in `src` through `transform` into `dst`
+pub(crate) fn pump<T, R, W>(mut transform: T, src: &mut R, dst: &mut W) -> anyhow::Result<T::Output>
+where
+    T: Transform,
+    R: Read,
+    W: Write,
+{
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut output_buffer = Vec::new();
+    let mut total_input_len = 0;
+    let mut total_output_len = 0;
+    loop {
+        let bytes_read = src.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        total_input_len += bytes_read;
+        output_buffer.clear();
+        transform.update_into(&buffer[..bytes_read], &mut output_buffer)?;
+        total_output_len += output_buffer.len();
+        dst.write_all(&output_buffer)?;
+    }
+
+    output_buffer.clear();
+    let output = transform.finalize_into(&mut output_buffer)?;
+    total_output_len += output_buffer.len();
+    dst.write_all(&output_buffer)?;
+    log::trace!("total_input_len: {}", total_input_len);
+    log::trace!("total_output_len: {}", total_output_len);
+    dst.flush()?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7u8; 32];
+    const NONCE: [u8; AES_GCM_NONCE_LEN] = [9u8; AES_GCM_NONCE_LEN];
+    const CHUNK_SIZE: u32 = 16;
+
+    fn seal_all(plaintext: &[u8]) -> Vec<u8> {
+        let mut encryptor = StreamEncryptor::new(KEY, NONCE, CHUNK_SIZE);
+        let mut out = Vec::new();
+        for piece in plaintext.chunks(5) {
+            encryptor.update(piece, &mut out).unwrap();
+        }
+        encryptor.finalize(&mut out).unwrap();
+        out
+    }
+
+    fn open_all(ciphertext: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut decryptor = StreamDecryptor::new(KEY, NONCE, CHUNK_SIZE);
+        let mut out = Vec::new();
+        for piece in ciphertext.chunks(7) {
+            decryptor.update(piece, &mut out)?;
+        }
+        decryptor.finalize(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn roundtrip_chunk_boundaries() {
+        for len in [0usize, 1, 15, 16, 17, 32, 33, 100] {
+            let plaintext: Vec<u8> = (0..len).map(|v| v as u8).collect();
+            let ciphertext = seal_all(&plaintext);
+            let chunks = std::cmp::max(1, len.div_ceil(16));
+            assert_eq!(ciphertext.len(), len + chunks * AES_GCM_TAG_LEN);
+            assert_eq!(open_all(&ciphertext).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn truncated_at_chunk_boundary() {
+        let plaintext = [1u8; 48];
+        let ciphertext = seal_all(&plaintext);
+        let truncated = &ciphertext[..2 * (CHUNK_SIZE as usize + AES_GCM_TAG_LEN)];
+        assert!(open_all(truncated).is_err());
+    }
+
+    #[test]
+    fn reordered_chunks() {
+        let plaintext: Vec<u8> = (0..48u8).collect();
+        let mut ciphertext = seal_all(&plaintext);
+        let len = CHUNK_SIZE as usize + AES_GCM_TAG_LEN;
+        let (first, second) = ciphertext.split_at_mut(len);
+        first.swap_with_slice(&mut second[..len]);
+        assert!(open_all(&ciphertext).is_err());
+    }
+
+    #[test]
+    fn tampered_chunk_emits_only_verified_chunks() {
+        let plaintext: Vec<u8> = (0..48u8).collect();
+        let mut ciphertext = seal_all(&plaintext);
+        ciphertext[CHUNK_SIZE as usize + AES_GCM_TAG_LEN + 1] ^= 0x80;
+
+        let mut decryptor = StreamDecryptor::new(KEY, NONCE, CHUNK_SIZE);
+        let mut out = Vec::new();
+        assert!(decryptor.update(&ciphertext, &mut out).is_err());
+        assert_eq!(out, &plaintext[..CHUNK_SIZE as usize]);
+    }
+
+    #[test]
+    fn associated_data_must_match() {
+        let mut encryptor = StreamEncryptor::new(KEY, NONCE, CHUNK_SIZE).with_aad(b"header");
+        let mut ciphertext = Vec::new();
+        encryptor.update(b"payload", &mut ciphertext).unwrap();
+        encryptor.finalize(&mut ciphertext).unwrap();
+
+        let mut out = Vec::new();
+        let mut decryptor = StreamDecryptor::new(KEY, NONCE, CHUNK_SIZE).with_aad(b"header");
+        decryptor.update(&ciphertext, &mut out).unwrap();
+        decryptor.finalize(&mut out).unwrap();
+        assert_eq!(out, b"payload");
+
+        let mut decryptor = StreamDecryptor::new(KEY, NONCE, CHUNK_SIZE).with_aad(b"Header");
+        decryptor.update(&ciphertext, &mut Vec::new()).unwrap();
+        assert!(decryptor.finalize(&mut Vec::new()).is_err());
+    }
+}
@@ -1,4 +1,5 @@
 use rand::Rng;
+use std::fs::{File, OpenOptions};
 use std::{fmt::Write, num::ParseIntError};
 
 pub(crate) fn decode_hex(s: &str) -> Result<Vec<u8>, ParseIntError> {
@@ -16,9 +17,37 @@ pub(crate) fn encode_hex(bytes: &[u8]) -> String {
     s
 }
 
-pub(crate) fn generate_random_iv() -> [u8; 16] {
+pub(crate) fn generate_random_bytes<const N: usize>() -> [u8; N] {
     let mut rng = rand::thread_rng();
-    let mut iv = [0u8; 16];
-    rng.fill(&mut iv);
-    iv
+    let mut bytes = [0u8; N];
+    rng.fill(&mut bytes[..]);
+    bytes
+}
+
+// A read-write file in the temp directory for data too large to keep in
+// memory. No other process can open it by name: it is unlinked right away on
+// Unix and deleted on close on Windows.
+pub(crate) fn spool_file() -> std::io::Result<File> {
+    let name = format!(
+        "file_cipher-{}.spool",
+        encode_hex(&generate_random_bytes::<16>())
+    );
+    let path = std::env::temp_dir().join(name);
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        const FILE_FLAG_DELETE_ON_CLOSE: u32 = 0x0400_0000;
+        options.custom_flags(FILE_FLAG_DELETE_ON_CLOSE);
+    }
+    let file = options.open(&path)?;
+    #[cfg(unix)]
+    std::fs::remove_file(&path)?;
+    Ok(file)
 }
@@ -2,6 +2,17 @@
 pub enum Version {
     V1,
     V2,
+    V3,
+    V4,
+    V5,
+}
+
+impl Version {
+    // Formats 1 and 2 have no authentication tag, damage or tampering goes
+    // unnoticed when decrypting
+    pub fn is_authenticated(&self) -> bool {
+        !matches!(self, Version::V1 | Version::V2)
+    }
 }
 
 impl From<Version> for u16 {
@@ -9,6 +20,9 @@ impl From<Version> for u16 {
         match value {
             Version::V1 => 0x0001,
             Version::V2 => 0x0002,
+            Version::V3 => 0x0003,
+            Version::V4 => 0x0004,
+            Version::V5 => 0x0005,
         }
     }
 }
@@ -19,6 +33,9 @@ impl TryFrom<u16> for Version {
         match value {
             0x0001 => Ok(Version::V1),
             0x0002 => Ok(Version::V2),
+            0x0003 => Ok(Version::V3),
+            0x0004 => Ok(Version::V4),
+            0x0005 => Ok(Version::V5),
             _ => Err("Unsupported format"),
         }
     }
@@ -12,7 +12,7 @@ pub struct XorCipher {
 
 impl XorCipher {
     pub fn new(xor: u8) -> Self {
-        XorCipher { xor: xor }
+        XorCipher { xor }
     }
 }
 
@@ -25,7 +25,7 @@ impl Cipher for XorCipher {
         let header = header::XorHeader::new();
         let mut header_bytes = [0u8; header::XorHeader::BYTE_LEN];
         header.write_bytes(&mut header_bytes);
-        dst.write(&header_bytes)?;
+        dst.write_all(&header_bytes)?;
 
         let mut buffer = vec![0u8; 1024];
         loop {